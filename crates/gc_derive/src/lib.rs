@@ -13,6 +13,7 @@ fn derive_trace(mut s: Structure<'_>) -> proc_macro2::TokenStream {
     let trace_body = s.each(|bi| quote!(mark(#bi)));
 
     s.add_bounds(AddBounds::Fields);
+    s.underscore_const(true);
     let trace_impl = s.unsafe_bound_impl(
         quote!(::rox_gc::Trace),
         quote! {
@@ -75,6 +76,7 @@ fn derive_trace(mut s: Structure<'_>) -> proc_macro2::TokenStream {
 
 decl_derive!([Finalize] => derive_finalize);
 
-fn derive_finalize(s: Structure<'_>) -> proc_macro2::TokenStream {
+fn derive_finalize(mut s: Structure<'_>) -> proc_macro2::TokenStream {
+    s.underscore_const(true);
     s.unbound_impl(quote!(::rox_gc::Finalize), quote!())
 }
@@ -2,6 +2,7 @@ use std::{
     cell::{Cell, RefCell},
     mem,
     ptr::{self, NonNull},
+    rc::Rc,
 };
 
 use crate::Trace;
@@ -55,6 +56,8 @@ thread_local! {
 /// Collects garbage
 fn collect_garbage(st: &mut GcState) {
     st.stats.collections_perfomed += 1;
+    st.stats.epoch += 1;
+    st.stats.objects_freed_last_cycle = 0;
 
     struct Unmarked<'a> {
         incoming: &'a Cell<Option<NonNull<GcBox<dyn Trace>>>>,
@@ -98,9 +101,10 @@ fn collect_garbage(st: &mut GcState) {
         unmarked
     }
 
-    // Sweep the tree, dropping all unmarked nodes
-    unsafe fn sweep(finalized: Vec<Unmarked<'_>>, bytes_allocated: &mut usize) {
+    // Sweep the tree, dropping all unmarked nodes, returns how many were dropped
+    unsafe fn sweep(finalized: Vec<Unmarked<'_>>, bytes_allocated: &mut usize) -> usize {
         let _guard = DropGuard::new();
+        let mut freed = 0;
         for node in finalized.into_iter().rev() {
             if (*node.this.as_ptr()).header.is_marked() {
                 // Don't claim the memory if it's still marked
@@ -114,7 +118,12 @@ fn collect_garbage(st: &mut GcState) {
             *bytes_allocated -= mem::size_of_val::<GcBox<_>>(&*node);
             // Take the value and lave `None` in its place
             incoming.set(node.header.next.take());
+            if let Some(alive) = node.header.weak.take() {
+                alive.set(false);
+            }
+            freed += 1;
         }
+        freed
     }
 
     unsafe {
@@ -122,19 +131,48 @@ fn collect_garbage(st: &mut GcState) {
         if unmarked.is_empty() {
             return;
         }
+        // `Finalize::finalize` runs exactly once per object. A finalizer may keep another
+        // unreachable object alive, that one survives this sweep and must not be finalized again
+        // when a later collection reclaims it.
         for node in unmarked.iter() {
-            Trace::finalize_glue(&(*node.this.as_ptr()).data);
+            let gcbox = &*node.this.as_ptr();
+            if !gcbox.header.finalized.replace(true) {
+                Trace::finalize_glue(&gcbox.data);
+            }
         }
-        mark(&st.box_start);
-        sweep(unmarked, &mut st.stats.bytes_allocated);
+        // marking unmarks the reachable nodes again, so only the nodes still unreachable after
+        // the finalizers ran are swept
+        let unmarked = mark(&st.box_start);
+        st.stats.objects_freed_last_cycle = sweep(unmarked, &mut st.stats.bytes_allocated);
     }
 }
 
+/// Runs a collection right away, whatever the threshold is
+pub fn force_collect() {
+    GC_STATE.with(|st| collect_garbage(&mut st.borrow_mut()));
+}
+
+/// Replaces the collector config of the current thread, e.g. to raise the threshold before a
+/// program starts allocating
+pub fn configure(config: GcConfig) {
+    GC_STATE.with(|st| st.borrow_mut().config = config);
+}
+
+/// A snapshot of the collector stats of the current thread
+pub fn stats() -> GcStats {
+    GC_STATE.with(|st| st.borrow().stats.clone())
+}
+
+#[derive(Clone, Debug)]
 pub struct GcStats {
     /// The number of bytes allocated by the GC
     pub bytes_allocated: usize,
     /// Collections since the last time the stats were reset
     pub collections_perfomed: usize,
+    /// Objects dropped by the most recent collection
+    pub objects_freed_last_cycle: usize,
+    /// Increases with every collection and is never reset, tells two snapshots apart
+    pub epoch: usize,
 }
 
 impl Default for GcStats {
@@ -142,12 +180,15 @@ impl Default for GcStats {
         Self {
             bytes_allocated: 0,
             collections_perfomed: 0,
+            objects_freed_last_cycle: 0,
+            epoch: 0,
         }
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct GcConfig {
-    /// The threshold at which the GC will run
+    /// The threshold in bytes at which the GC will run
     pub threshold: usize,
     /// After collection we want to he ratio of used/total to be no more than this
     pub used_space_ratio: f64,
@@ -168,6 +209,10 @@ impl Default for GcConfig {
 pub struct GcBoxHeader {
     roots: Cell<usize>,
     next: Cell<Option<NonNull<GcBox<dyn Trace>>>>,
+    // set once `Finalize::finalize` ran for the data
+    finalized: Cell<bool>,
+    // shared with the `GcWeak`s of this box, created by the first of them
+    weak: Cell<Option<Rc<Cell<bool>>>>,
 }
 
 impl GcBoxHeader {
@@ -176,6 +221,8 @@ impl GcBoxHeader {
         Self {
             roots: Cell::new(1),
             next: Cell::new(next),
+            finalized: Cell::new(false),
+            weak: Cell::new(None),
         }
     }
 
@@ -222,6 +269,8 @@ impl Default for GcBoxHeader {
         Self {
             roots: Cell::new(0),
             next: Cell::new(None),
+            finalized: Cell::new(false),
+            weak: Cell::new(None),
         }
     }
 }
@@ -293,6 +342,18 @@ impl<T: Trace + ?Sized> GcBox<T> {
         self.header.dec_roots();
     }
 
+    /// Returns the flag of the `GcWeak`s of this `GcBox`, the collector clears it when it frees
+    /// the `GcBox`
+    pub(crate) fn weak_alive(&self) -> Rc<Cell<bool>> {
+        let alive = self
+            .header
+            .weak
+            .take()
+            .unwrap_or_else(|| Rc::new(Cell::new(true)));
+        self.header.weak.set(Some(alive.clone()));
+        alive
+    }
+
     /// Returns a pointer to the `GcBox`'s value without dereferencing it
     pub fn value_ptr(this: *const GcBox<T>) -> *const T {
         unsafe { ptr::addr_of!((*this).data) }
@@ -303,3 +364,216 @@ impl<T: Trace + ?Sized> GcBox<T> {
         &self.data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Finalize, Gc, GcCell};
+
+    thread_local! {
+        static FINALIZED: Cell<usize> = Cell::new(0);
+        // a finalizer stores a `Gc` here to keep it alive past its own collection
+        static SAVED: RefCell<Option<Gc<Leaf>>> = RefCell::new(None);
+    }
+
+    fn finalized() -> usize {
+        FINALIZED.with(|count| count.get())
+    }
+
+    fn count_finalize() {
+        FINALIZED.with(|count| count.set(count.get() + 1));
+    }
+
+    struct Leaf;
+
+    impl Finalize for Leaf {
+        fn finalize(&self) {
+            count_finalize();
+        }
+    }
+
+    unsafe impl Trace for Leaf {
+        unsafe fn trace(&self) {}
+        unsafe fn root(&self) {}
+        unsafe fn unroot(&self) {}
+        fn finalize_glue(&self) {
+            Finalize::finalize(self);
+        }
+    }
+
+    struct Node {
+        next: GcCell<Option<Gc<Node>>>,
+    }
+
+    impl Finalize for Node {
+        fn finalize(&self) {
+            count_finalize();
+        }
+    }
+
+    unsafe impl Trace for Node {
+        unsafe fn trace(&self) {
+            self.next.trace();
+        }
+        unsafe fn root(&self) {
+            self.next.root();
+        }
+        unsafe fn unroot(&self) {
+            self.next.unroot();
+        }
+        fn finalize_glue(&self) {
+            Finalize::finalize(self);
+            self.next.finalize_glue();
+        }
+    }
+
+    struct Saver {
+        leaf: Gc<Leaf>,
+    }
+
+    impl Finalize for Saver {
+        fn finalize(&self) {
+            let leaf = self.leaf.clone();
+            SAVED.with(|saved| *saved.borrow_mut() = Some(leaf));
+        }
+    }
+
+    unsafe impl Trace for Saver {
+        unsafe fn trace(&self) {
+            self.leaf.trace();
+        }
+        unsafe fn root(&self) {
+            self.leaf.root();
+        }
+        unsafe fn unroot(&self) {
+            self.leaf.unroot();
+        }
+        fn finalize_glue(&self) {
+            Finalize::finalize(self);
+            self.leaf.finalize_glue();
+        }
+    }
+
+    // reads its leaf again when it's dropped, which the sweep doesn't allow
+    struct Peeker {
+        leaf: Gc<Leaf>,
+    }
+
+    impl Finalize for Peeker {
+        fn finalize(&self) {
+            let _leaf: &Leaf = &self.leaf;
+        }
+    }
+
+    impl Drop for Peeker {
+        fn drop(&mut self) {
+            Finalize::finalize(self);
+        }
+    }
+
+    unsafe impl Trace for Peeker {
+        unsafe fn trace(&self) {
+            self.leaf.trace();
+        }
+        unsafe fn root(&self) {
+            self.leaf.root();
+        }
+        unsafe fn unroot(&self) {
+            self.leaf.unroot();
+        }
+        fn finalize_glue(&self) {
+            Finalize::finalize(self);
+            self.leaf.finalize_glue();
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "dereferencing a `Gc<rox_gc::gc::tests::Leaf>` while the collector drops objects is forbidden"
+    )]
+    fn test_deref_during_sweep_panics() {
+        drop(Gc::new(Peeker {
+            leaf: Gc::new(Leaf),
+        }));
+        force_collect();
+    }
+
+    #[test]
+    fn test_finalize_once_in_cycle() {
+        force_collect();
+        let a = Gc::new(Node {
+            next: GcCell::new(None),
+        });
+        let b = Gc::new(Node {
+            next: GcCell::new(Some(a.clone())),
+        });
+        *a.next.borrow_mut() = Some(b.clone());
+        drop(a);
+        drop(b);
+
+        force_collect();
+        assert_eq!(2, finalized());
+        assert_eq!(2, stats().objects_freed_last_cycle);
+        force_collect();
+        assert_eq!(2, finalized());
+    }
+
+    #[test]
+    fn test_finalize_once_after_finalizer_keeps_object_alive() {
+        force_collect();
+        drop(Gc::new(Saver {
+            leaf: Gc::new(Leaf),
+        }));
+
+        // the leaf is finalized with the saver but `SAVED` keeps it alive
+        force_collect();
+        assert_eq!(1, finalized());
+        assert_eq!(1, stats().objects_freed_last_cycle);
+
+        SAVED.with(|saved| saved.borrow_mut().take());
+        force_collect();
+        assert_eq!(1, stats().objects_freed_last_cycle);
+        assert_eq!(1, finalized());
+    }
+
+    #[test]
+    fn test_configure_threshold() {
+        let allocate = || {
+            let before = stats().collections_perfomed;
+            for idx in 0..100usize {
+                drop(Gc::new(idx));
+            }
+            stats().collections_perfomed - before
+        };
+
+        configure(GcConfig::default());
+        let low = allocate();
+        configure(GcConfig {
+            threshold: usize::MAX,
+            ..GcConfig::default()
+        });
+        assert_eq!(0, allocate());
+        assert!(low > 0);
+        configure(GcConfig::default());
+    }
+
+    #[test]
+    fn test_objects_freed_last_cycle() {
+        force_collect();
+        let before = stats();
+
+        let objects: Vec<Gc<usize>> = (0..10).map(Gc::new).collect();
+        let kept = Gc::new(42usize);
+        drop(objects);
+        force_collect();
+
+        let after = stats();
+        assert_eq!(10, after.objects_freed_last_cycle);
+        assert!(after.epoch > before.epoch);
+        assert_eq!(42, *kept);
+
+        // nothing left to free
+        force_collect();
+        assert_eq!(0, stats().objects_freed_last_cycle);
+    }
+}
@@ -3,7 +3,7 @@ mod trace;
 
 use core::fmt;
 use std::{
-    cell::{Cell, UnsafeCell},
+    cell::{Cell, OnceCell, UnsafeCell},
     cmp::Ordering,
     fmt::Display,
     hash::{self, Hasher},
@@ -14,7 +14,7 @@ use std::{
     rc::Rc,
 };
 
-pub use crate::gc::{finalizer_safe, GcBox};
+pub use crate::gc::{configure, finalizer_safe, force_collect, stats, GcBox, GcConfig, GcStats};
 pub use crate::trace::{Finalize, Trace};
 
 pub struct Gc<T: Trace + ?Sized + 'static> {
@@ -57,6 +57,145 @@ impl<T: Trace> Gc<T> {
             gc
         }
     }
+
+    /// Constructs a `Gc<T>` whose value holds a `GcWeak` to itself, e.g. a node which points back
+    /// at its parent.
+    ///
+    /// `f` runs before the value is allocated, so upgrading the `GcWeak` inside `f` gives `None`.
+    /// Once `new_cyclic` returned every clone of it upgrades to the new `Gc`.
+    ///
+    /// # Collection
+    ///
+    /// This method could trigger a garbage collection.
+    pub fn new_cyclic<F: FnOnce(&GcWeak<T>) -> T>(f: F) -> Self {
+        let weak = GcWeak::new();
+        let gc = Gc::new(f(&weak));
+        let _ = weak.slot.alive.set(gc.inner().weak_alive());
+        weak.slot
+            .ptr
+            .set(Some(unsafe { clear_root_bit(gc.ptr_root.get()) }));
+        gc
+    }
+}
+
+/// A `Gc` whose value can be mutated through any of its clones.
+///
+/// # Examples
+///
+/// ```
+/// use rox_gc::GcMut;
+///
+/// let counter = GcMut::new_mut(0usize);
+/// let shared = counter.clone();
+///
+/// *counter.borrow_mut() += 1;
+/// *shared.borrow_mut() += 1;
+/// assert_eq!(2, *counter.borrow());
+/// ```
+pub type GcMut<T> = Gc<GcCell<T>>;
+
+impl<T: Trace> Gc<GcCell<T>> {
+    /// Constructs a new `GcMut<T>`, the same as `Gc::new(GcCell::new(value))`.
+    ///
+    /// # Collection
+    ///
+    /// This method could trigger a garbage collection.
+    pub fn new_mut(value: T) -> Self {
+        Gc::new(GcCell::new(value))
+    }
+}
+
+impl<T: Trace + ?Sized> Gc<GcCell<T>> {
+    /// Immutably borrows the wrapped value, see [`GcCell::borrow`].
+    #[inline]
+    pub fn borrow(&self) -> GcCellRef<'_, T> {
+        GcCell::borrow(self)
+    }
+
+    /// Mutably borrows the wrapped value, see [`GcCell::borrow_mut`].
+    #[inline]
+    pub fn borrow_mut(&self) -> GcCellRefMut<'_, T> {
+        GcCell::borrow_mut(self)
+    }
+}
+
+// Shared by the clones of a `GcWeak`, both are only set once the value is allocated
+struct WeakSlot<T: Trace + ?Sized + 'static> {
+    ptr: Cell<Option<NonNull<GcBox<T>>>>,
+    alive: OnceCell<Rc<Cell<bool>>>,
+}
+
+/// A pointer to a `Gc` value which doesn't keep it alive, created by [`Gc::downgrade`] or
+/// [`Gc::new_cyclic`].
+///
+/// It isn't traced, a value only reachable through `GcWeak`s is collected and upgrading them
+/// gives `None` afterwards.
+pub struct GcWeak<T: Trace + ?Sized + 'static> {
+    slot: Rc<WeakSlot<T>>,
+}
+
+impl<T: Trace> GcWeak<T> {
+    /// Constructs a `GcWeak` which never upgrades, like `std::rc::Weak::new`.
+    pub fn new() -> Self {
+        GcWeak {
+            slot: Rc::new(WeakSlot {
+                ptr: Cell::new(None),
+                alive: OnceCell::new(),
+            }),
+        }
+    }
+}
+
+impl<T: Trace> Default for GcWeak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Trace + ?Sized> GcWeak<T> {
+    /// Returns a rooted `Gc` to the value, or `None` once the value was collected.
+    pub fn upgrade(&self) -> Option<Gc<T>> {
+        let alive = self.slot.alive.get().is_some_and(|alive| alive.get());
+        match self.slot.ptr.get() {
+            Some(ptr) if alive => unsafe {
+                let gc = Gc {
+                    ptr_root: Cell::new(ptr),
+                    marker: PhantomData,
+                };
+                gc.inner().root_inner();
+                gc.set_root();
+                Some(gc)
+            },
+            _ => None,
+        }
+    }
+}
+
+impl<T: Trace + ?Sized> Clone for GcWeak<T> {
+    fn clone(&self) -> Self {
+        GcWeak {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T: Trace + ?Sized> Finalize for GcWeak<T> {}
+
+// A `GcWeak` holds no roots and isn't traced
+unsafe impl<T: Trace + ?Sized> Trace for GcWeak<T> {
+    #[inline]
+    unsafe fn trace(&self) {}
+
+    #[inline]
+    unsafe fn root(&self) {}
+
+    #[inline]
+    unsafe fn unroot(&self) {}
+
+    #[inline]
+    fn finalize_glue(&self) {
+        Finalize::finalize(self)
+    }
 }
 
 unsafe fn clear_root_bit<T: Trace + ?Sized>(ptr: NonNull<GcBox<T>>) -> NonNull<GcBox<T>> {
@@ -73,6 +212,23 @@ impl<T: Trace + ?Sized> Gc<T> {
         GcBox::ptr_eq(this.inner(), other.inner())
     }
 
+    /// Returns a raw pointer to the value, e.g. to key a map on the identity of a `Gc`.
+    ///
+    /// The pointer is never dereferenced, so unlike `Deref` it can be called at any time.
+    pub fn as_ptr(this: &Self) -> *const T {
+        unsafe { GcBox::value_ptr(clear_root_bit(this.ptr_root.get()).as_ptr()) }
+    }
+
+    /// Creates a `GcWeak` to the value, it doesn't keep the value alive.
+    pub fn downgrade(this: &Self) -> GcWeak<T> {
+        GcWeak {
+            slot: Rc::new(WeakSlot {
+                ptr: Cell::new(Some(unsafe { clear_root_bit(this.ptr_root.get()) })),
+                alive: OnceCell::from(this.inner().weak_alive()),
+            }),
+        }
+    }
+
     fn rooted(&self) -> bool {
         self.ptr_root.get().as_ptr() as *mut u8 as usize & 1 != 0
     }
@@ -98,8 +254,14 @@ impl<T: Trace + ?Sized> Gc<T> {
         // By opting into `Trace` you are agreeing to not dereference the pointer
         // within your drop method, meaning that it should be safe.
         //
-        // This assert exists just in case.
-        assert!(finalizer_safe());
+        // This assert exists just in case, a `Drop` which calls `finalize` while the sweep runs
+        // would otherwise read a `GcBox` which may already be freed.
+        assert!(
+            finalizer_safe(),
+            "dereferencing a `Gc<{}>` while the collector drops objects is forbidden, \
+             `Drop` and any `finalize` it calls must not touch other `Gc`s",
+            std::any::type_name::<T>()
+        );
 
         unsafe { clear_root_bit(self.ptr_root.get()).as_ptr() }
     }
@@ -376,9 +538,43 @@ impl<T: Trace> GcCell<T> {
     pub fn into_inner(self) -> T {
         self.cell.into_inner()
     }
+
+    /// Replaces the wrapped value with `value` and returns the old one.
+    ///
+    /// It goes through `borrow_mut`, so the old value comes out rooted and the new one is unrooted
+    /// again if the `GcCell` lives in the gc heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    #[inline]
+    pub fn replace(&self, value: T) -> T {
+        mem::replace(&mut *self.borrow_mut(), value)
+    }
+}
+
+impl<T: Trace + Default> GcCell<T> {
+    /// Takes the wrapped value and leaves `Default::default()` in its place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    #[inline]
+    pub fn take(&self) -> T {
+        self.replace(Default::default())
+    }
 }
 
 impl<T: Trace + ?Sized> GcCell<T> {
+    /// Returns a mutable reference to the wrapped value.
+    ///
+    /// The `&mut self` guarantees there is no borrow, and a `GcCell` inside the gc heap is only
+    /// reachable through a shared reference, so the contents already carry the right roots.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.cell.get_mut()
+    }
+
     /// Immutably borrows the wrapped value.
     ///
     /// The borrow lasts until the returned `GcCellRef` exits scope.
@@ -818,6 +1014,10 @@ impl<'a, T: Trace + ?Sized, U: ?Sized> GcCellRefMut<'a, T, U> {
     /// }
     /// assert_eq!(*c.borrow(), (42, 'b'));
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// A panic in `f` releases the borrow, the `GcCell` stays usable.
     #[inline]
     pub fn map<V, F>(orig: Self, f: F) -> GcCellRefMut<'a, T, V>
     where
@@ -826,6 +1026,8 @@ impl<'a, T: Trace + ?Sized, U: ?Sized> GcCellRefMut<'a, T, U> {
     {
         let value = unsafe { &mut *(orig.value as *mut U) };
 
+        // `orig` is only forgotten once `f` returned, if `f` panics `orig` is dropped while
+        // unwinding, which releases the borrow and restores the roots of the contents
         let ret = GcCellRefMut {
             gc_cell: orig.gc_cell,
             value: f(value),
@@ -848,3 +1050,146 @@ unsafe fn set_data_ptr<T: ?Sized, U>(mut ptr: *mut T, data: *mut U) -> *mut T {
     ptr::write(&mut ptr as *mut _ as *mut *mut u8, data as *mut u8);
     ptr
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_as_ptr_identity() {
+        let a = Gc::new(String::from("a"));
+        let same = Gc::new(String::from("a"));
+        let mut seen = HashMap::new();
+        for gc in [a.clone(), a.clone(), same.clone()] {
+            *seen.entry(Gc::as_ptr(&gc)).or_insert(0) += 1;
+        }
+        assert_eq!(2, seen.len());
+        assert_eq!(Some(&2), seen.get(&Gc::as_ptr(&a)));
+        assert_eq!(&*a as *const String, Gc::as_ptr(&a));
+    }
+
+    #[test]
+    fn test_gc_cell_replace() {
+        let cell = GcCell::new(5usize);
+        assert_eq!(5, cell.replace(6));
+        assert_eq!(6, *cell.borrow());
+    }
+
+    #[test]
+    #[should_panic(expected = "GcCell<T> already borrowed")]
+    fn test_gc_cell_replace_while_borrowed() {
+        let cell = GcCell::new(5usize);
+        let _borrow = cell.borrow();
+        cell.replace(6);
+    }
+
+    #[test]
+    fn test_gc_cell_take() {
+        let cell = GcCell::new(String::from("a"));
+        assert_eq!("a", cell.take());
+        assert_eq!("", *cell.borrow());
+    }
+
+    #[test]
+    fn test_gc_cell_get_mut() {
+        let mut cell = GcCell::new(5usize);
+        *cell.get_mut() += 1;
+        assert_eq!(6, cell.into_inner());
+    }
+
+    #[test]
+    fn test_gc_cell_replace_roots() {
+        let cell = Gc::new(GcCell::new(Some(Gc::new(String::from("old")))));
+        let old = cell.replace(Some(Gc::new(String::from("new"))));
+
+        // the cell keeps the new value alive, the old one is only kept alive by its root
+        force_collect();
+        assert_eq!(0, stats().objects_freed_last_cycle);
+        assert_eq!("new", cell.borrow().as_ref().unwrap().as_str());
+
+        let old = old.unwrap();
+        assert_eq!("old", old.as_str());
+        drop(old);
+        force_collect();
+        assert_eq!(1, stats().objects_freed_last_cycle);
+        assert_eq!("new", cell.borrow().as_ref().unwrap().as_str());
+    }
+
+    #[test]
+    fn test_gc_cell_ref_mut_map_panics() {
+        let cell = Gc::new(GcCell::new(Some(Gc::new(String::from("a")))));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = GcCellRefMut::map(cell.borrow_mut(), |_| -> &mut String { panic!("map") });
+        }));
+        assert!(result.is_err());
+
+        // the borrow was released and the contents are unrooted again
+        assert!(cell.try_borrow_mut().is_ok());
+        drop(cell);
+        force_collect();
+        assert_eq!(2, stats().objects_freed_last_cycle);
+    }
+
+    // `next` keeps the other node alive, `prev` points back without doing so
+    struct Ring {
+        next: Option<Gc<Ring>>,
+        prev: GcWeak<Ring>,
+    }
+
+    impl Finalize for Ring {}
+
+    unsafe impl Trace for Ring {
+        unsafe fn trace(&self) {
+            self.next.trace();
+        }
+        unsafe fn root(&self) {
+            self.next.root();
+        }
+        unsafe fn unroot(&self) {
+            self.next.unroot();
+        }
+        fn finalize_glue(&self) {
+            Finalize::finalize(self);
+            self.next.finalize_glue();
+        }
+    }
+
+    #[test]
+    fn test_new_cyclic() {
+        let first = Gc::new_cyclic(|first: &GcWeak<Ring>| {
+            // nothing to upgrade to while the value is built
+            assert!(first.upgrade().is_none());
+            Ring {
+                next: Some(Gc::new(Ring {
+                    next: None,
+                    prev: first.clone(),
+                })),
+                prev: GcWeak::new(),
+            }
+        });
+        let second = first.next.as_ref().unwrap();
+        assert!(Gc::ptr_eq(&first, &second.prev.upgrade().unwrap()));
+        assert!(first.prev.upgrade().is_none());
+
+        let weak = Gc::downgrade(&first);
+        drop(first);
+        force_collect();
+        assert_eq!(2, stats().objects_freed_last_cycle);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_weak_does_not_keep_alive() {
+        let value = Gc::new(String::from("a"));
+        let weak = Gc::downgrade(&value);
+        force_collect();
+        assert_eq!("a", weak.upgrade().unwrap().as_str());
+
+        drop(value);
+        force_collect();
+        assert_eq!(1, stats().objects_freed_last_cycle);
+        assert!(weak.clone().upgrade().is_none());
+    }
+}
@@ -1,6 +1,10 @@
 /// A trait which needs to be implemented on garbage collected
 pub trait Finalize {
     /// finalize is called when the object is about to be dropped
+    ///
+    /// The collector calls it before sweeping, where other `Gc`s may still be dereferenced. Once
+    /// the sweep started dereferencing a `Gc` panics, so a `Drop` impl must not call `finalize`
+    /// if it touches other `Gc`s.
     fn finalize(&self) {}
 }
 
@@ -1,4 +1,8 @@
+use std::ops::Range;
+
+use crate::objects::HashKeyString;
 use crate::op_code::OpCode;
+use crate::utils::hash;
 use crate::value::Value;
 
 use gc_derive::{Finalize, Trace};
@@ -8,6 +12,10 @@ pub struct Chunk {
     pub code: Vec<OpCode>,
     pub constants: Vec<Value>,
     pub lines: Vec<usize>,
+    // The hashed key of every string constant, built once so global access does not rehash the
+    // name each time the instruction runs. `None` for other constants.
+    #[unsafe_ignore_trace]
+    pub keys: Vec<Option<HashKeyString>>,
 }
 
 impl Chunk {
@@ -18,6 +26,7 @@ impl Chunk {
             //TODO: use hash table to store constants?
             constants: Vec::new(),
             lines: Vec::new(),
+            keys: Vec::new(),
         }
     }
 
@@ -26,12 +35,38 @@ impl Chunk {
         self.push_line(line);
     }
 
+    // An equal number or string already in the pool is reused instead of stored again, numbers
+    // are compared bit for bit so `0` and `-0` stay apart
     pub fn push_constant(&mut self, constant: Value) -> usize {
+        let existing = self.constants.iter().position(|c| match (c, &constant) {
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (Value::String(a), Value::String(b)) => a == b,
+            _ => false,
+        });
+        if let Some(index) = existing {
+            return index;
+        }
+
+        let key = match &constant {
+            Value::String(s) => Some(HashKeyString {
+                hash: hash(s),
+                value: s.to_string(),
+            }),
+            _ => None,
+        };
+        self.keys.push(key);
         self.constants.push(constant);
         // return the index of the constant
         self.constants.len() - 1
     }
 
+    // The key of the global named by constant `index`
+    pub fn global_key(&self, index: usize) -> &HashKeyString {
+        self.keys[index]
+            .as_ref()
+            .expect("global name must be a string constant")
+    }
+
     pub fn len(&self) -> usize {
         self.code.len()
     }
@@ -47,6 +82,58 @@ impl Chunk {
     pub fn push_line(&mut self, line: usize) {
         self.lines.push(line);
     }
+
+    // Replace the instructions in `range` with `replacement` and rewrite every jump outside the
+    // range so it still lands on the same instruction. A jump into the range lands on the first
+    // replacement instruction, the replacement itself is kept as given and takes the line of the
+    // first replaced instruction.
+    pub fn splice(&mut self, range: Range<usize>, replacement: Vec<OpCode>) {
+        let removed = range.len();
+        let added = replacement.len();
+        let moved = |old: usize| {
+            if old < range.start {
+                old
+            } else {
+                old + added - removed
+            }
+        };
+        let target = |old: usize| {
+            if old <= range.start {
+                old
+            } else if old >= range.end {
+                old + added - removed
+            } else {
+                range.start
+            }
+        };
+        let offset = |distance: usize| u16::try_from(distance).expect("Jump offset too large");
+
+        for old in (0..self.code.len()).filter(|old| !range.contains(old)) {
+            let at = moved(old);
+            self.code[old] = match self.code[old] {
+                OpCode::Jump(v) => OpCode::Jump(offset(target(old + 1 + v as usize) - at - 1)),
+                OpCode::JumpIfFalse(v) => {
+                    OpCode::JumpIfFalse(offset(target(old + 1 + v as usize) - at - 1))
+                }
+                OpCode::JumpIfTrue(v) => {
+                    OpCode::JumpIfTrue(offset(target(old + 1 + v as usize) - at - 1))
+                }
+                OpCode::JumpIfNotNil(v) => {
+                    OpCode::JumpIfNotNil(offset(target(old + 1 + v as usize) - at - 1))
+                }
+                OpCode::Loop(v) => OpCode::Loop(offset(at + 1 - target(old + 1 - v as usize))),
+                instruction => instruction,
+            };
+        }
+
+        let line = match self.lines.get(range.start) {
+            Some(line) => *line,
+            None => self.lines.last().copied().unwrap_or(0),
+        };
+        self.lines
+            .splice(range.clone(), std::iter::repeat_n(line, added));
+        self.code.splice(range, replacement);
+    }
     // FIXME - Chunk should have a name then we can disassemble?
     pub fn disassemble_chunk(&self, name: &str) {
         println!("== Begin to disassemble {} ==", name);
@@ -56,31 +143,48 @@ impl Chunk {
         }
     }
 
-    #[allow(unreachable_patterns)]
     pub fn disassemble_instruction(&self, offset: usize) {
         println!("offset -> {}", offset);
         let instruction = &self.code[offset];
         let line = &self.lines[offset];
         match instruction {
-            OpCode::Call(v) => self.constant_instruction("Call", Some(*v), offset, *line),
+            // the operand is an element count rather than a constant index
+            OpCode::BuildList(_) => self.constant_instruction("Build List", None, offset, *line),
+            // the operand of calls, locals and upvalues is a count or a slot rather than a constant
+            // index
+            OpCode::Call(_) => self.constant_instruction("Call", None, offset, *line),
             OpCode::Closure(v) => self.constant_instruction("Closure", Some(*v), offset, *line),
             OpCode::CloseUpvalue => self.constant_instruction("CloseUpValue", None, offset, *line),
             OpCode::Constant(v) => self.constant_instruction("Constant", Some(*v), offset, *line),
             OpCode::Negative => self.constant_instruction("Negative", None, offset, *line),
             OpCode::Return => self.constant_instruction("Return", None, offset, *line),
+            OpCode::ReturnNil => self.constant_instruction("Return Nil", None, offset, *line),
             OpCode::Add => self.constant_instruction("Add", None, offset, *line),
             OpCode::Subtract => self.constant_instruction("Subtract", None, offset, *line),
+            OpCode::Xor => self.constant_instruction("Xor", None, offset, *line),
+            OpCode::Modulo => self.constant_instruction("Modulo", None, offset, *line),
             OpCode::Multiply => self.constant_instruction("Multiply", None, offset, *line),
             OpCode::Divide => self.constant_instruction("Divide", None, offset, *line),
             OpCode::Nil => self.constant_instruction("Nil", None, offset, *line),
             OpCode::True => self.constant_instruction("True", None, offset, *line),
             OpCode::False => self.constant_instruction("False", None, offset, *line),
             OpCode::Not => self.constant_instruction("Not", None, offset, *line),
+            OpCode::NotEqual => self.constant_instruction("Not Equal", None, offset, *line),
             OpCode::Equal => self.constant_instruction("Equal", None, offset, *line),
             OpCode::Greater => self.constant_instruction("Greater", None, offset, *line),
+            OpCode::GreaterEqual => self.constant_instruction("Greater Equal", None, offset, *line),
+            OpCode::Identical => self.constant_instruction("Identical", None, offset, *line),
+            OpCode::Index => self.constant_instruction("Index", None, offset, *line),
+            OpCode::IndexSet => self.constant_instruction("Index Set", None, offset, *line),
             OpCode::Less => self.constant_instruction("Less", None, offset, *line),
+            OpCode::LessEqual => self.constant_instruction("Less Equal", None, offset, *line),
             OpCode::Print => self.constant_instruction("Print", None, offset, *line),
+            OpCode::PrintMulti(_) => self.constant_instruction("PrintMulti", None, offset, *line),
             OpCode::Pop => self.constant_instruction("Pop", None, offset, *line),
+            // the operand is a count rather than a constant index
+            OpCode::PopN(_) => self.constant_instruction("PopN", None, offset, *line),
+            OpCode::Positive => self.constant_instruction("Positive", None, offset, *line),
+            OpCode::Power => self.constant_instruction("Power", None, offset, *line),
             OpCode::SetGlobal(v) => {
                 self.constant_instruction("Set Global", Some(*v), offset, *line)
             }
@@ -90,22 +194,19 @@ impl Chunk {
             OpCode::DefineGlobal(v) => {
                 self.constant_instruction("Define Global", Some(*v), offset, *line)
             }
-            OpCode::GetLocal(v) => self.constant_instruction("Get Local", Some(*v), offset, *line),
-            OpCode::GetUpvalue(v) => {
-                self.constant_instruction("Get Upvalue", Some(*v), offset, *line)
+            OpCode::GetLocal(_) => self.constant_instruction("Get Local", None, offset, *line),
+            OpCode::GetUpvalue(_) => self.constant_instruction("Get Upvalue", None, offset, *line),
+            OpCode::SetLocal(_) => self.constant_instruction("Set Local", None, offset, *line),
+            OpCode::SetUpvalue(_) => self.constant_instruction("Set Upvalue", None, offset, *line),
+            OpCode::Jump(_) => self.constant_instruction("Jump", None, offset, *line),
+            OpCode::JumpIfFalse(_) => {
+                self.constant_instruction("Jump If False", None, offset, *line)
             }
-            OpCode::SetLocal(v) => self.constant_instruction("Set Local", Some(*v), offset, *line),
-            OpCode::SetUpvalue(v) => {
-                self.constant_instruction("Set Upvalue", Some(*v), offset, *line)
+            OpCode::JumpIfTrue(_) => self.constant_instruction("Jump If True", None, offset, *line),
+            OpCode::JumpIfNotNil(_) => {
+                self.constant_instruction("Jump If Not Nil", None, offset, *line)
             }
-            OpCode::Call(v) => self.constant_instruction("Function", Some(*v), offset, *line),
-            OpCode::GetUpvalue(v) => {
-                self.constant_instruction("Get Upvalue", Some(*v), offset, *line)
-            }
-            OpCode::SetUpvalue(v) => {
-                self.constant_instruction("Set Upvalue", Some(*v), offset, *line)
-            }
-            _ => println!("Unknown opcode {}", instruction),
+            OpCode::Loop(_) => self.constant_instruction("Loop", None, offset, *line),
         }
     }
 
@@ -134,6 +235,8 @@ impl Default for Chunk {
 
 #[cfg(test)]
 mod tests {
+    use rox_gc::Gc;
+
     use super::*;
 
     #[test]
@@ -162,6 +265,40 @@ mod tests {
         assert_eq!(index, 0);
     }
 
+    #[test]
+    fn test_push_constant_dedup() {
+        let mut chunk = Chunk::new();
+        let first = chunk.push_constant(Value::Number(1.0));
+        let second = chunk.push_constant(Value::Number(1.0));
+        assert_eq!(first, second);
+        assert_eq!(chunk.constants.len(), 1);
+
+        let a = chunk.push_constant(Value::String(Gc::new(String::from("a"))));
+        assert_eq!(
+            a,
+            chunk.push_constant(Value::String(Gc::new(String::from("a"))))
+        );
+        assert_ne!(first, chunk.push_constant(Value::Number(-1.0)));
+        assert_eq!(chunk.constants.len(), 3);
+    }
+
+    #[test]
+    fn test_push_constant_key() {
+        let mut chunk = Chunk::new();
+        let number = chunk.push_constant(Value::Number(1.0));
+        let name = chunk.push_constant(Value::String(Gc::new(String::from("name"))));
+        assert_eq!(None, chunk.keys[number]);
+        assert_eq!(hash("name"), chunk.global_key(name).hash);
+        assert_eq!("name", chunk.global_key(name).value);
+    }
+
+    #[test]
+    fn test_push_constant_keeps_negative_zero() {
+        let mut chunk = Chunk::new();
+        let zero = chunk.push_constant(Value::Number(0.0));
+        assert_ne!(zero, chunk.push_constant(Value::Number(-0.0)));
+    }
+
     #[test]
     fn test_len() {
         let mut chunk = Chunk::new();
@@ -250,4 +387,87 @@ mod tests {
         chunk.push_instruction(code_nil);
         assert_eq!(1, chunk.len());
     }
+
+    fn chunk_of(code: Vec<OpCode>) -> Chunk {
+        let mut chunk = Chunk::new();
+        for (line, instruction) in code.into_iter().enumerate() {
+            chunk.write_to_chunk(instruction, line + 1);
+        }
+        chunk
+    }
+
+    #[test]
+    fn test_splice_remove_before_forward_jump() {
+        // the jump lands on Print
+        let mut chunk = chunk_of(vec![
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::Jump(1),
+            OpCode::True,
+            OpCode::Print,
+        ]);
+        chunk.splice(0..2, vec![]);
+        assert_eq!(
+            vec![OpCode::Jump(1), OpCode::True, OpCode::Print],
+            chunk.code
+        );
+        assert_eq!(vec![3, 4, 5], chunk.lines);
+    }
+
+    #[test]
+    fn test_splice_inside_forward_jump() {
+        let mut chunk = chunk_of(vec![
+            OpCode::JumpIfFalse(3),
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Print,
+        ]);
+        chunk.splice(1..3, vec![OpCode::Nil, OpCode::Pop, OpCode::Nil]);
+        assert_eq!(OpCode::JumpIfFalse(4), chunk.code[0]);
+        assert_eq!(OpCode::Print, chunk.code[5]);
+        assert_eq!(vec![1, 2, 2, 2, 4, 5], chunk.lines);
+    }
+
+    #[test]
+    fn test_splice_inside_loop() {
+        // the loop lands on True
+        let mut chunk = chunk_of(vec![
+            OpCode::Nil,
+            OpCode::True,
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Loop(4),
+        ]);
+        chunk.splice(2..4, vec![OpCode::PopN(2)]);
+        assert_eq!(
+            vec![OpCode::Nil, OpCode::True, OpCode::PopN(2), OpCode::Loop(3)],
+            chunk.code
+        );
+        assert_eq!(vec![1, 2, 3, 5], chunk.lines);
+    }
+
+    #[test]
+    fn test_splice_jump_into_range() {
+        let mut chunk = chunk_of(vec![
+            OpCode::Jump(1),
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::Print,
+        ]);
+        chunk.splice(1..3, vec![OpCode::Nil]);
+        assert_eq!(
+            vec![OpCode::Jump(0), OpCode::Nil, OpCode::Print],
+            chunk.code
+        );
+    }
+
+    #[test]
+    fn test_splice_insert() {
+        let mut chunk = chunk_of(vec![OpCode::Jump(1), OpCode::Nil, OpCode::Print]);
+        chunk.splice(1..1, vec![OpCode::True, OpCode::Pop]);
+        assert_eq!(OpCode::Jump(3), chunk.code[0]);
+        assert_eq!(OpCode::Print, chunk.code[4]);
+        assert_eq!(vec![1, 2, 2, 2, 3], chunk.lines);
+    }
 }
@@ -1,41 +1,50 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
 use rox_gc::Gc;
 
 use crate::chunk::Chunk;
-use crate::objects::{ObjFunction, UpValue, MAX_UPVALUES};
+use crate::limits::{MAX_ARGS, MAX_LOCALS, MAX_PARAMS, MAX_UPVALUES};
+use crate::objects::{ObjFunction, UpValue};
 use crate::op_code::OpCode;
-use crate::scanner::Scanner;
+use crate::optimizer::optimize;
+use crate::scanner::{error_message, Scanner};
 use crate::token::{Token, TokenType};
-use crate::utils::convert_slice_to_string;
+use crate::utils::{convert_slice_to_string, unescape};
 use crate::value::Value;
 
-const MAX_LOCALS: usize = 256;
-
 //FIXME - remove dead_code
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 // Precedence symbols from low to high:
 //  No -> no Precedence
 //  Assignment -> =
 //  Or -> or
+//  Coalesce -> ??
+//  Xor -> xor
 //  And -> and
-//  Equality -> == !=
+//  Equality -> == != ===
 //  Comparison -> < > <= >=
 //  Term -> + -
-//  Factor -> * /
-//  Unary -> ! -
-//  Call -> . ()
+//  Factor -> * / %
+//  Unary -> ! - +
+//  Power -> **
+//  Call -> () []
 //  Primary -> literals and grouping
 //
 enum Precedence {
     No,
     Assignment,
     Or,
+    Coalesce,
+    Xor,
     And,
     Equality,
     Comparison,
     Term,
     Factor,
     Unary,
+    Power,
     Call,
     Primary,
 }
@@ -45,13 +54,16 @@ impl Precedence {
         match self {
             Precedence::No => Precedence::Assignment,
             Precedence::Assignment => Precedence::Or,
-            Precedence::Or => Precedence::And,
+            Precedence::Or => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Xor,
+            Precedence::Xor => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
             Precedence::Comparison => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
+            Precedence::Unary => Precedence::Power,
+            Precedence::Power => Precedence::Call,
             Precedence::Call => Precedence::Primary,
             Precedence::Primary => Precedence::Primary,
         }
@@ -60,17 +72,44 @@ impl Precedence {
 
 type ParseFn<'a> = fn(&mut Parser<'a>, can_assign: bool) -> ();
 
+#[derive(Clone, Copy)]
 struct ParseRule<'a> {
+    token: TokenType,
     prefix: Option<ParseFn<'a>>,
     infix: Option<ParseFn<'a>>,
     precedence: Precedence,
 }
 
+impl<'a> ParseRule<'a> {
+    const fn new(
+        token: TokenType,
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            token,
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 struct Local {
     name: Token,
     depth: i32,
     is_captured: bool, // This field is `true` if the local is captured by any later closure.
+    is_const: bool,
+    // read or captured before its scope ends, locals starting with `_` never warn
+    used: bool,
+}
+
+// A loop being compiled, its `break` jumps are patched once the loop ends
+struct Loop {
+    scope_depth: i32,
+    breaks: Vec<usize>,
 }
 
 #[derive(PartialEq, Eq)]
@@ -88,6 +127,8 @@ pub struct Compiler {
     function_type: FunctionType,
     // each compiler points to the enclosing compiler
     enclosing: Option<Box<Compiler>>,
+    // innermost loop last, `break` never crosses a function boundary so each compiler has its own
+    loops: Vec<Loop>,
 }
 
 impl Compiler {
@@ -100,9 +141,14 @@ impl Compiler {
                         start: 0,
                         length: 0,
                         line: 0,
+                        column: 0,
+                        end_line: 0,
+                        end_column: 0,
                     },
                     depth: 0,
                     is_captured: false,
+                    is_const: false,
+                    used: true,
                 };
                 MAX_LOCALS
             ],
@@ -111,67 +157,117 @@ impl Compiler {
             function: ObjFunction::new(name),
             function_type: types,
             enclosing: None,
+            loops: Vec::new(),
         }
     }
 
-    fn resolve_local(&mut self, bytes: &[u8], name: &Token) -> Option<usize> {
+    // Fails when the local is still being declared, e.g. `var a = a;`
+    fn resolve_local(&mut self, bytes: &[u8], name: &Token) -> Result<Option<usize>, &'static str> {
         let token_literal = &bytes[name.start..name.start + name.length];
         for idx in (0..self.local_count).rev() {
             let local = self.locals[idx];
             let local_literal = &bytes[local.name.start..local.name.start + local.name.length];
             if local_literal == token_literal {
-                return Some(idx);
+                if local.depth == -1 {
+                    return Err("Can't read local variable in its own initializer.");
+                }
+                return Ok(Some(idx));
             }
         }
-        None
+        Ok(None)
     }
 
-    fn resolve_upvalue(&mut self, bytes: &[u8], name: &Token) -> Option<usize> {
+    // Fails when the closure captures more than `MAX_UPVALUES` variables
+    fn resolve_upvalue(
+        &mut self,
+        bytes: &[u8],
+        name: &Token,
+    ) -> Result<Option<usize>, &'static str> {
         // First, we look for a matching local variable in the current enclosing function.
         // If we find one, we capture and return the index of local variable in the enclosing function.
         if let Some(enclosing) = self.enclosing.as_mut() {
-            if let Some(index) = enclosing.resolve_local(bytes, name) {
+            if let Some(index) = enclosing.resolve_local(bytes, name)? {
                 // When resolving an identifier, if we end up creating a new upvalue for a local
                 // var, we mark it as captured.
                 enclosing.locals[index].is_captured = true;
-                return Some(self.add_upvalue(index, true));
+                enclosing.locals[index].used = true;
+                return self.add_upvalue(index, true).map(Some);
             }
             // Otherwise, we look for a local variable beyond the immediate enclosing function recursively.
             // When a local variable is found, the most deeply nested call to resolve_upvalue captures it
             // and returns the index.
 
-            if let Some(index) = enclosing.resolve_upvalue(bytes, name) {
-                return Some(self.add_upvalue(index, false));
+            if let Some(index) = enclosing.resolve_upvalue(bytes, name)? {
+                return self.add_upvalue(index, false).map(Some);
             }
         }
-        None
+        Ok(None)
     }
 
-    fn add_upvalue(&mut self, index: usize, is_local: bool) -> usize {
+    // Whether the variable an upvalue resolves to was declared with `const`
+    fn is_const_upvalue(&self, bytes: &[u8], name: &Token) -> bool {
+        let mut enclosing = self.enclosing.as_deref();
+        while let Some(compiler) = enclosing {
+            let token_literal = &bytes[name.start..name.start + name.length];
+            for local in compiler.locals[..compiler.local_count].iter().rev() {
+                if &bytes[local.name.start..local.name.start + local.name.length] == token_literal {
+                    return local.is_const;
+                }
+            }
+            enclosing = compiler.enclosing.as_deref();
+        }
+        false
+    }
+
+    fn add_upvalue(&mut self, index: usize, is_local: bool) -> Result<usize, &'static str> {
         let count = self.function.upvalues.len();
-        for value in self.function.upvalues.iter() {
+        // a variable captured again reuses its slot in the upvalues
+        for (slot, value) in self.function.upvalues.iter().enumerate() {
             if value.index == index && value.is_local == is_local {
-                return value.index;
+                return Ok(slot);
             }
         }
 
         if count == MAX_UPVALUES {
-            // TODO -  propagate error back to the parser
-            panic!("Too many closure variables in function.");
+            return Err("Too many closure variables in function.");
         }
 
         self.function.upvalues.push(UpValue { index, is_local });
-        count
+        Ok(count)
     }
 }
 
+// A compiled script and what the compiler learned about its globals
+pub(crate) struct Script {
+    pub function: ObjFunction,
+    pub echo: bool,
+    pub const_globals: HashSet<String>,
+}
+
 pub struct Parser<'a> {
     scanner: Scanner<'a>,
     compiler: Compiler,
     current: Token,
     previous: Token,
-    had_error: bool,
+    // Every error reported so far, the script only compiles when there is none
+    errors: Vec<String>,
     panic_mode: bool,
+    // In REPL mode a trailing top-level expression statement returns its value from the script
+    // instead of popping it, `echo` records whether that happened
+    repl: bool,
+    echo: bool,
+    // Run the peephole optimizer over every compiled function
+    optimize: bool,
+    // Every string constant of the script, functions included, shares one `Gc` per content
+    strings: HashMap<String, Gc<String>>,
+    // With `check_globals` every global the script reads or assigns must be one of these or be
+    // defined somewhere in the script, forward references to later definitions are fine.
+    known_globals: Option<HashSet<String>>,
+    global_uses: Vec<Token>,
+    // Globals declared with `const`, by this script or by the earlier REPL lines
+    const_globals: HashSet<String>,
+    // Where the left operand of the infix rule being compiled starts in the chunk
+    operand_start: usize,
 }
 
 impl<'a> Parser<'a> {
@@ -184,30 +280,58 @@ impl<'a> Parser<'a> {
                 start: 0,
                 length: 0,
                 line: 0,
+                column: 0,
+                end_line: 0,
+                end_column: 0,
             },
             previous: Token {
                 t_type: TokenType::Nil,
                 start: 0,
                 length: 0,
                 line: 0,
+                column: 0,
+                end_line: 0,
+                end_column: 0,
             },
-            had_error: false,
+            errors: Vec::new(),
             panic_mode: false,
+            repl: false,
+            echo: false,
+            optimize: false,
+            strings: HashMap::new(),
+            known_globals: None,
+            global_uses: Vec::new(),
+            const_globals: HashSet::new(),
+            operand_start: 0,
         }
     }
 
+    // Warn about every global which is neither in `known`, e.g. the natives and the globals of
+    // earlier REPL lines, nor defined by the script. Mostly catches typos, `pritn(1);` compiles
+    // but always fails at runtime.
+    pub fn check_globals<I: IntoIterator<Item = String>>(&mut self, known: I) {
+        self.known_globals = Some(known.into_iter().collect());
+    }
+
+    // Constants declared by earlier scripts, e.g. the previous REPL lines, can't be assigned or
+    // redefined either
+    pub fn const_globals<I: IntoIterator<Item = String>>(&mut self, known: I) {
+        self.const_globals.extend(known);
+    }
+
+    pub fn set_optimize(&mut self, enabled: bool) {
+        self.optimize = enabled;
+    }
+
     fn next_valid_token(&mut self) {
         self.previous = self.current;
 
         loop {
             self.current = self.scanner.scan_token();
 
-            if self.current.t_type == TokenType::Error {
-                let start = self.current.start;
-                let end = start + self.current.length;
-                self.error_at_current(&convert_slice_to_string(self.scanner.bytes, start, end));
-            } else {
-                break;
+            match error_message(self.scanner.bytes, &self.current) {
+                Some(msg) => self.error_at_current(&msg),
+                None => break,
             }
         }
     }
@@ -225,18 +349,27 @@ impl<'a> Parser<'a> {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] error", token.line);
+        let mut report = format!("[line {}:{}] Error", token.line, token.column);
         if token.t_type == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.t_type == TokenType::Error {
-            eprint!(" unknown type found.");
-        } else {
-            eprint!(" at {} {}", token.length, token.start);
+            report.push_str(" at end");
+        } else if token.t_type != TokenType::Error {
+            // the message of an error token already describes the lexeme
+            report.push_str(&format!(
+                " at '{}'",
+                String::from_utf8_lossy(
+                    &self.scanner.bytes[token.start..token.start + token.length]
+                )
+            ));
         }
+        report.push_str(&format!(": {}", msg));
 
-        eprint!(" : {}", msg);
+        eprintln!("{}", report);
+        self.errors.push(report);
+    }
 
-        self.had_error = true;
+    // Warnings are reported but never fail the compilation
+    fn warning_at(&mut self, token: Token, msg: &str) {
+        eprintln!("[line {}:{}] Warning: {}", token.line, token.column, msg);
     }
 
     // The current function chunk is always the chunk owned by the function we're in the middle of compiling.
@@ -267,6 +400,7 @@ impl<'a> Parser<'a> {
 
             match self.current.t_type {
                 TokenType::Class
+                | TokenType::Const
                 | TokenType::Fun
                 | TokenType::Var
                 | TokenType::For
@@ -285,28 +419,16 @@ impl<'a> Parser<'a> {
         self.current.t_type == t
     }
 
-    fn patch_jump(&mut self, offset: usize) {
-        let jump_offset = self.current_function_chunk().code.len() - offset - 1;
-
-        if jump_offset > u16::MAX as usize {
-            self.error("Too much code to jump over.");
-        }
-
-        let new_code = OpCode::Jump(jump_offset as u16);
-
-        self.current_function_chunk_mut().code[offset] = new_code;
-    }
-
-    fn patch_if_false_jump(&mut self, offset: usize) {
+    // Point the jump at `offset` to the next instruction, `jump` builds the patched opcode, e.g.
+    // `OpCode::JumpIfFalse`
+    fn patch_jump_with(&mut self, offset: usize, jump: fn(u16) -> OpCode) {
         let jump_offset = self.current_function_chunk().code.len() - offset - 1;
 
         if jump_offset > u16::MAX as usize {
             self.error("Too much code to jump over.");
         }
 
-        let new_code = OpCode::JumpIfFalse(jump_offset as u16);
-
-        self.current_function_chunk_mut().code[offset] = new_code;
+        self.current_function_chunk_mut().code[offset] = jump(jump_offset as u16);
     }
 
     fn match_token(&mut self, token_type: TokenType) -> bool {
@@ -317,91 +439,175 @@ impl<'a> Parser<'a> {
         true
     }
 
-    fn get_rule(&mut self, t: TokenType) -> ParseRule<'a> {
-        match t {
-            TokenType::LeftParen => ParseRule {
-                prefix: Some(Parser::grouping),
-                infix: Some(Parser::call),
-                precedence: Precedence::Call,
-            },
-            TokenType::Minus => ParseRule {
-                prefix: Some(Parser::unary),
-                infix: Some(Parser::binary),
-                precedence: Precedence::Term,
-            },
-            TokenType::Bang => ParseRule {
-                prefix: Some(Parser::unary),
-                infix: None,
-                precedence: Precedence::Term,
-            },
-            TokenType::BangEqual | TokenType::EqualEqual => ParseRule {
-                prefix: None,
-                infix: Some(Parser::binary),
-                precedence: Precedence::Equality,
-            },
-            TokenType::Plus => ParseRule {
-                prefix: None,
-                infix: Some(Parser::binary),
-                precedence: Precedence::Term,
-            },
-            TokenType::Slash | TokenType::Star => ParseRule {
-                prefix: None,
-                infix: Some(Parser::binary),
-                precedence: Precedence::Factor,
-            },
-            TokenType::Number => ParseRule {
-                prefix: Some(Parser::number),
-                infix: None,
-                precedence: Precedence::No,
-            },
-            TokenType::Nil | TokenType::True | TokenType::False => ParseRule {
-                prefix: Some(Parser::literal),
-                infix: None,
-                precedence: Precedence::No,
-            },
-            TokenType::Greater
-            | TokenType::GreaterEqual
-            | TokenType::Less
-            | TokenType::LessEqual => ParseRule {
-                prefix: None,
-                infix: Some(Parser::binary),
-                precedence: Precedence::Comparison,
-            },
-            TokenType::Print => ParseRule {
-                prefix: Some(Parser::print),
-                infix: None,
-                precedence: Precedence::No,
-            },
-            TokenType::Strings => ParseRule {
-                prefix: Some(Parser::string),
-                infix: None,
-                precedence: Precedence::No,
-            },
-            TokenType::Identifier => ParseRule {
-                prefix: Some(Parser::parse_variable),
-                infix: None,
-                precedence: Precedence::No,
-            },
-            TokenType::And => ParseRule {
-                prefix: None,
-                infix: Some(Parser::and),
-                precedence: Precedence::And,
-            },
-            TokenType::Or => ParseRule {
-                prefix: None,
-                infix: Some(Parser::or),
-                precedence: Precedence::Or,
-            },
-            _ => ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::No,
-            },
-        }
+    // One rule per token type in the order of `TokenType`, `test_rules` checks the order
+    const RULES: [ParseRule<'a>; TokenType::COUNT] = [
+        ParseRule::new(
+            TokenType::LeftParen,
+            Some(Parser::grouping),
+            Some(Parser::call),
+            Precedence::Call,
+        ),
+        ParseRule::new(TokenType::RightParen, None, None, Precedence::No),
+        ParseRule::new(TokenType::LeftBrace, None, None, Precedence::No),
+        ParseRule::new(TokenType::RightBrace, None, None, Precedence::No),
+        ParseRule::new(
+            TokenType::LeftBracket,
+            Some(Parser::list),
+            Some(Parser::index),
+            Precedence::Call,
+        ),
+        ParseRule::new(TokenType::RightBracket, None, None, Precedence::No),
+        ParseRule::new(TokenType::Comma, None, None, Precedence::No),
+        ParseRule::new(TokenType::Dot, None, None, Precedence::No),
+        ParseRule::new(
+            TokenType::Minus,
+            Some(Parser::unary),
+            Some(Parser::binary),
+            Precedence::Term,
+        ),
+        ParseRule::new(
+            TokenType::Plus,
+            Some(Parser::unary),
+            Some(Parser::binary),
+            Precedence::Term,
+        ),
+        ParseRule::new(TokenType::Semicolon, None, None, Precedence::No),
+        ParseRule::new(
+            TokenType::Slash,
+            None,
+            Some(Parser::binary),
+            Precedence::Factor,
+        ),
+        ParseRule::new(
+            TokenType::Star,
+            None,
+            Some(Parser::binary),
+            Precedence::Factor,
+        ),
+        ParseRule::new(
+            TokenType::Percent,
+            None,
+            Some(Parser::binary),
+            Precedence::Factor,
+        ),
+        ParseRule::new(TokenType::Bang, Some(Parser::unary), None, Precedence::No),
+        ParseRule::new(
+            TokenType::BangEqual,
+            None,
+            Some(Parser::binary),
+            Precedence::Equality,
+        ),
+        ParseRule::new(TokenType::Equal, None, None, Precedence::No),
+        ParseRule::new(
+            TokenType::EqualEqual,
+            None,
+            Some(Parser::binary),
+            Precedence::Equality,
+        ),
+        ParseRule::new(
+            TokenType::EqualEqualEqual,
+            None,
+            Some(Parser::binary),
+            Precedence::Equality,
+        ),
+        ParseRule::new(
+            TokenType::Greater,
+            None,
+            Some(Parser::binary),
+            Precedence::Comparison,
+        ),
+        ParseRule::new(
+            TokenType::GreaterEqual,
+            None,
+            Some(Parser::binary),
+            Precedence::Comparison,
+        ),
+        ParseRule::new(
+            TokenType::Less,
+            None,
+            Some(Parser::binary),
+            Precedence::Comparison,
+        ),
+        ParseRule::new(
+            TokenType::LessEqual,
+            None,
+            Some(Parser::binary),
+            Precedence::Comparison,
+        ),
+        ParseRule::new(
+            TokenType::StarStar,
+            None,
+            Some(Parser::binary),
+            Precedence::Power,
+        ),
+        ParseRule::new(
+            TokenType::QuestionQuestion,
+            None,
+            Some(Parser::coalesce),
+            Precedence::Coalesce,
+        ),
+        ParseRule::new(
+            TokenType::Identifier,
+            Some(Parser::parse_variable),
+            None,
+            Precedence::No,
+        ),
+        ParseRule::new(
+            TokenType::Strings,
+            Some(Parser::string),
+            None,
+            Precedence::No,
+        ),
+        ParseRule::new(
+            TokenType::RawStrings,
+            Some(Parser::string),
+            None,
+            Precedence::No,
+        ),
+        ParseRule::new(
+            TokenType::Number,
+            Some(Parser::number),
+            None,
+            Precedence::No,
+        ),
+        ParseRule::new(TokenType::And, None, Some(Parser::and), Precedence::And),
+        ParseRule::new(TokenType::Break, None, None, Precedence::No),
+        ParseRule::new(TokenType::Class, None, None, Precedence::No),
+        ParseRule::new(TokenType::Const, None, None, Precedence::No),
+        ParseRule::new(TokenType::Else, None, None, Precedence::No),
+        ParseRule::new(
+            TokenType::False,
+            Some(Parser::literal),
+            None,
+            Precedence::No,
+        ),
+        ParseRule::new(TokenType::Fun, Some(Parser::lambda), None, Precedence::No),
+        ParseRule::new(TokenType::For, None, None, Precedence::No),
+        ParseRule::new(TokenType::If, None, None, Precedence::No),
+        ParseRule::new(TokenType::Nil, Some(Parser::literal), None, Precedence::No),
+        ParseRule::new(TokenType::Or, None, Some(Parser::or), Precedence::Or),
+        ParseRule::new(TokenType::Print, Some(Parser::print), None, Precedence::No),
+        ParseRule::new(TokenType::Return, None, None, Precedence::No),
+        ParseRule::new(TokenType::Super, None, None, Precedence::No),
+        ParseRule::new(TokenType::This, None, None, Precedence::No),
+        ParseRule::new(TokenType::True, Some(Parser::literal), None, Precedence::No),
+        ParseRule::new(TokenType::Var, None, None, Precedence::No),
+        ParseRule::new(TokenType::While, None, None, Precedence::No),
+        // both sides are always evaluated, there is nothing to short-circuit
+        ParseRule::new(TokenType::Xor, None, Some(Parser::binary), Precedence::Xor),
+        ParseRule::new(TokenType::Error, None, None, Precedence::No),
+        ParseRule::new(TokenType::Eof, None, None, Precedence::No),
+    ];
+
+    fn get_rule(&self, t: TokenType) -> ParseRule<'a> {
+        let rule = Self::RULES[t as usize];
+        debug_assert_eq!(t, rule.token);
+        rule
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.next_valid_token();
+        let start = self.current_function_chunk().code.len();
 
         let prefix_rule = match self.get_rule(self.previous.t_type).prefix {
             Some(rule) => rule,
@@ -414,9 +620,21 @@ impl<'a> Parser<'a> {
         let can_assign = precedence <= Precedence::Assignment;
         prefix_rule(self, can_assign);
 
+        // `1 < 2 < 3` would compare the bool of `1 < 2` with 3, it is rejected here instead of
+        // failing at runtime. A parenthesized comparison is its own expression and is not caught.
+        let mut after_comparison = false;
         while precedence <= self.get_rule(self.current.t_type).precedence {
             self.next_valid_token();
-            if let Some(infix_rule) = self.get_rule(self.previous.t_type).infix {
+            let rule = self.get_rule(self.previous.t_type);
+            let is_comparison = rule.precedence == Precedence::Comparison;
+            if after_comparison && is_comparison {
+                self.error(
+                    "Comparison operators can't be chained, combine the comparisons with 'and'.",
+                );
+            }
+            after_comparison = is_comparison;
+            if let Some(infix_rule) = rule.infix {
+                self.operand_start = start;
                 infix_rule(self, can_assign);
             }
         }
@@ -443,40 +661,108 @@ impl<'a> Parser<'a> {
 
     fn unary(&mut self, _: bool) {
         let operator_type = self.previous.t_type;
+        let line = self.previous.line;
+        let start = self.current_function_chunk().code.len();
 
         self.parse_precedence(Precedence::Unary);
+        let end = self.current_function_chunk().code.len();
 
         match operator_type {
-            TokenType::Minus => {
-                self.emit_byte(OpCode::Negative);
-            }
+            TokenType::Minus => match self.constant_number(start..end) {
+                Some(x) => self.fold_constant(start, -x),
+                None => self.emit_byte_at(OpCode::Negative, line),
+            },
+            TokenType::Plus => match self.constant_number(start..end) {
+                Some(x) => self.fold_constant(start, x),
+                None => self.emit_byte_at(OpCode::Positive, line),
+            },
             TokenType::Bang => {
-                self.emit_byte(OpCode::Not);
+                self.emit_byte_at(OpCode::Not, line);
             }
             _ => (),
         }
     }
 
+    // The instructions get the line of the operator, not of the end of the right operand, so a
+    // runtime error in `a +\n nil` points at the `+`
+    // `**` is right associative, its right operand is parsed at its own precedence so
+    // `2 ** 3 ** 2` is `2 ** (3 ** 2)`
     fn binary(&mut self, _: bool) {
         let operator_type = self.previous.t_type;
+        let line = self.previous.line;
+        let left = self.operand_start;
+        let right = self.current_function_chunk().code.len();
         let rule = self.get_rule(operator_type);
-        self.parse_precedence(rule.precedence.next());
+        if operator_type == TokenType::StarStar {
+            self.parse_precedence(rule.precedence);
+        } else {
+            self.parse_precedence(rule.precedence.next());
+        }
+
+        // arithmetic on two number literals computes its result here, the vm computes the same
+        // f64. Dividing by zero is left to the vm so it's still a runtime error.
+        let end = self.current_function_chunk().code.len();
+        let operands = (
+            self.constant_number(left..right),
+            self.constant_number(right..end),
+        );
+        if let (Some(a), Some(b)) = operands {
+            let folded = match operator_type {
+                TokenType::Plus => Some(a + b),
+                TokenType::Minus => Some(a - b),
+                TokenType::Star => Some(a * b),
+                TokenType::Slash if b != 0.0 => Some(a / b),
+                TokenType::Percent if b != 0.0 => Some(a % b),
+                TokenType::StarStar => Some(a.powf(b)),
+                _ => None,
+            };
+            if let Some(value) = folded {
+                self.fold_constant(left, value);
+                return;
+            }
+        }
 
         match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::BangEqual => self.emit_two_bytes(OpCode::Equal, OpCode::Not),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_two_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_two_bytes(OpCode::Greater, OpCode::Not),
+            TokenType::Plus => self.emit_byte_at(OpCode::Add, line),
+            TokenType::Minus => self.emit_byte_at(OpCode::Subtract, line),
+            TokenType::Star => self.emit_byte_at(OpCode::Multiply, line),
+            TokenType::Slash => self.emit_byte_at(OpCode::Divide, line),
+            TokenType::Percent => self.emit_byte_at(OpCode::Modulo, line),
+            TokenType::StarStar => self.emit_byte_at(OpCode::Power, line),
+            TokenType::EqualEqual => self.emit_byte_at(OpCode::Equal, line),
+            TokenType::EqualEqualEqual => self.emit_byte_at(OpCode::Identical, line),
+            TokenType::BangEqual => self.emit_two_bytes_at(OpCode::Equal, OpCode::Not, line),
+            TokenType::Greater => self.emit_byte_at(OpCode::Greater, line),
+            TokenType::GreaterEqual => self.emit_two_bytes_at(OpCode::Less, OpCode::Not, line),
+            TokenType::Less => self.emit_byte_at(OpCode::Less, line),
+            TokenType::LessEqual => self.emit_two_bytes_at(OpCode::Greater, OpCode::Not, line),
+            TokenType::Xor => self.emit_byte_at(OpCode::Xor, line),
             _ => unreachable!("{:?}", operator_type),
         }
     }
 
+    // The number when the instructions in `range` are nothing but a number constant
+    fn constant_number(&self, range: Range<usize>) -> Option<f64> {
+        let chunk = self.current_function_chunk();
+        match chunk.code.get(range)? {
+            [OpCode::Constant(index)] => match chunk.constants[*index] {
+                Value::Number(n) => Some(n),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Replace everything from `start` on with the computed number
+    fn fold_constant(&mut self, start: usize, value: f64) {
+        let chunk = self.current_function_chunk_mut();
+        let line = chunk.lines[start];
+        chunk.code.truncate(start);
+        chunk.lines.truncate(start);
+        let index = chunk.push_constant(Value::Number(value));
+        self.emit_byte_at(OpCode::Constant(index), line);
+    }
+
     fn literal(&mut self, _: bool) {
         match self.previous.t_type {
             TokenType::False => self.emit_byte(OpCode::False),
@@ -487,16 +773,49 @@ impl<'a> Parser<'a> {
     }
 
     fn string(&mut self, _: bool) {
+        // the scanner works on bytes, a string is the first place they must be valid UTF-8
+        let lexeme =
+            &self.scanner.bytes[self.previous.start..self.previous.start + self.previous.length];
+        if std::str::from_utf8(lexeme).is_err() {
+            self.error("Strings must be valid UTF-8.");
+            return;
+        }
+
+        if self.previous.t_type == TokenType::RawStrings {
+            // skip the `r"` and the closing quote, the content is taken as written
+            let start = self.previous.start + 2;
+            let end = self.previous.start + self.previous.length - 1;
+            let value = convert_slice_to_string(self.scanner.bytes, start, end);
+            let value = self.intern(value);
+            self.emit_constant(Value::String(value));
+            return;
+        }
+
         let start = self.previous.start + 1;
         let length = self.previous.length - 2;
-        let value = convert_slice_to_string(self.scanner.bytes, start, start + length);
-        self.emit_constant(Value::String(Gc::new(value)));
+        match unescape(&self.scanner.bytes[start..start + length]) {
+            Ok(value) => {
+                let value = self.intern(value);
+                self.emit_constant(Value::String(value));
+            }
+            Err(msg) => self.error(&msg),
+        }
     }
 
+    // `print a, b;` prints every value on one line
     fn print(&mut self, _: bool) {
+        let mut count = 1;
         self.expression();
+        while self.match_token(TokenType::Comma) {
+            self.expression();
+            count += 1;
+        }
         self.consume(TokenType::Semicolon, "Expect ';' after value");
-        self.emit_byte(OpCode::Print);
+        if count == 1 {
+            self.emit_byte(OpCode::Print);
+        } else {
+            self.emit_byte(OpCode::PrintMulti(count));
+        }
     }
 
     fn variable(&mut self, msg: &str) -> usize {
@@ -507,6 +826,17 @@ impl<'a> Parser<'a> {
             return 0;
         }
 
+        let lexeme = convert_slice_to_string(
+            self.scanner.bytes,
+            self.previous.start,
+            self.previous.start + self.previous.length,
+        );
+        if self.const_globals.contains(&lexeme) {
+            self.error(&format!("Can't redefine constant '{}'.", lexeme));
+        }
+        if let Some(known) = self.known_globals.as_mut() {
+            known.insert(lexeme);
+        }
         self.identifier_constant()
     }
 
@@ -514,21 +844,58 @@ impl<'a> Parser<'a> {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse(0xff));
         self.emit_byte(OpCode::Pop);
         self.parse_precedence(Precedence::And);
-        self.patch_if_false_jump(end_jump);
+        self.patch_jump_with(end_jump, OpCode::JumpIfFalse);
     }
 
+    // A truthy left operand skips the right one and stays on the stack as the result, a falsey
+    // one is popped and the right operand becomes the result
     fn or(&mut self, _: bool) {
-        let end_jump = self.emit_jump(OpCode::Jump(0xff));
-
+        let end_jump = self.emit_jump(OpCode::JumpIfTrue(0xff));
         self.emit_byte(OpCode::Pop);
-
         self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_jump);
+        self.patch_jump_with(end_jump, OpCode::JumpIfTrue);
+    }
+
+    // A left operand other than nil skips the right one and stays on the stack as the result
+    fn coalesce(&mut self, _: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfNotNil(0xff));
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::Coalesce);
+        self.patch_jump_with(end_jump, OpCode::JumpIfNotNil);
     }
 
+    // A call spanning several lines is reported at its `(`
     fn call(&mut self, _: bool) {
+        let line = self.previous.line;
         let arg_count = self.argument_list();
-        self.emit_byte(OpCode::Call(arg_count));
+        self.emit_byte_at(OpCode::Call(arg_count), line);
+    }
+
+    fn list(&mut self, _: bool) {
+        let mut count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+        self.emit_byte(OpCode::BuildList(count));
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        let line = self.previous.line;
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte_at(OpCode::IndexSet, line);
+        } else {
+            self.emit_byte_at(OpCode::Index, line);
+        }
     }
 
     fn argument_list(&mut self) -> usize {
@@ -536,8 +903,8 @@ impl<'a> Parser<'a> {
         if !self.check(TokenType::RightParen) {
             loop {
                 self.expression();
-                if arg_count == 255 {
-                    self.error("Cannot have more than 255 arguments.");
+                if arg_count == MAX_ARGS {
+                    self.error(&format!("Cannot have more than {} arguments.", MAX_ARGS));
                 }
                 arg_count += 1;
 
@@ -593,6 +960,8 @@ impl<'a> Parser<'a> {
             name,
             depth: -1,
             is_captured: false,
+            is_const: false,
+            used: false,
         };
 
         std::mem::swap(
@@ -607,32 +976,57 @@ impl<'a> Parser<'a> {
     }
 
     fn compile_named_variable(&mut self, name: Token, can_assign: bool) {
-        let arg = self.compiler.resolve_local(self.scanner.bytes, &name);
+        let arg = match self.compiler.resolve_local(self.scanner.bytes, &name) {
+            Ok(arg) => arg,
+            Err(msg) => {
+                self.error_at(name, msg);
+                return;
+            }
+        };
         // Compiler walks the block scopes for the current function from innermost to outermost. If
         // it does not find the variable in the current scope, it looks for a local variable in any
         // of the surrounding functions
         match arg {
             Some(index) => {
                 if self.match_token(TokenType::Equal) && can_assign {
+                    if self.compiler.locals[index].is_const {
+                        self.const_assignment_error(name);
+                    }
                     self.expression();
 
                     self.emit_byte(OpCode::SetLocal(index));
                 } else {
+                    self.compiler.locals[index].used = true;
                     self.emit_byte(OpCode::GetLocal(index));
                 }
             }
             None => match self.compiler.resolve_upvalue(self.scanner.bytes, &name) {
-                Some(index) => {
+                Err(msg) => self.error(msg),
+                Ok(Some(index)) => {
                     if self.match_token(TokenType::Equal) && can_assign {
+                        if self.compiler.is_const_upvalue(self.scanner.bytes, &name) {
+                            self.const_assignment_error(name);
+                        }
                         self.expression();
                         self.emit_byte(OpCode::SetUpvalue(index));
                     } else {
                         self.emit_byte(OpCode::GetUpvalue(index));
                     }
                 }
-                None => {
+                Ok(None) => {
+                    if self.known_globals.is_some() {
+                        self.global_uses.push(name);
+                    }
                     let global = self.identifier_constant();
                     if self.match_token(TokenType::Equal) && can_assign {
+                        let lexeme = convert_slice_to_string(
+                            self.scanner.bytes,
+                            name.start,
+                            name.start + name.length,
+                        );
+                        if self.const_globals.contains(&lexeme) {
+                            self.const_assignment_error(name);
+                        }
                         self.expression();
                         self.emit_byte(OpCode::SetGlobal(global));
                     } else {
@@ -643,6 +1037,12 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn const_assignment_error(&mut self, name: Token) {
+        let lexeme =
+            convert_slice_to_string(self.scanner.bytes, name.start, name.start + name.length);
+        self.error_at(name, &format!("Can't assign to constant '{}'.", lexeme));
+    }
+
     fn identifier_constant(&mut self) -> usize {
         let identifier = convert_slice_to_string(
             self.scanner.bytes,
@@ -650,10 +1050,18 @@ impl<'a> Parser<'a> {
             self.previous.start + self.previous.length,
         );
 
+        let identifier = self.intern(identifier);
         self.compiler
             .function
             .chunk
-            .push_constant(Value::String(Gc::new(identifier)))
+            .push_constant(Value::String(identifier))
+    }
+
+    fn intern(&mut self, value: String) -> Gc<String> {
+        self.strings
+            .entry(value)
+            .or_insert_with_key(|value| Gc::new(value.clone()))
+            .clone()
     }
 
     fn emit_constant(&mut self, number: Value) {
@@ -667,16 +1075,14 @@ impl<'a> Parser<'a> {
         self.emit_byte(OpCode::Closure(index));
     }
 
-    fn emit_loop(&mut self, loop_start: u16) {
-        let len =
-            u16::try_from(self.current_function_chunk().code.len()).expect("Chunk code too large");
-
-        let offset = len - loop_start - 1;
-        if offset > 0xff {
-            self.error("Loop body too large.");
+    // `loop_start` is the index of the first instruction to run again, the offset is counted from
+    // the instruction after the loop like a forward jump
+    fn emit_loop(&mut self, loop_start: usize) {
+        let offset = self.current_function_chunk().code.len() - loop_start + 1;
+        match u16::try_from(offset) {
+            Ok(offset) => self.emit_byte(OpCode::Loop(offset)),
+            Err(_) => self.error("Loop body too large."),
         }
-
-        self.emit_byte(OpCode::Loop(offset))
     }
 
     fn emit_return(&mut self) {
@@ -685,15 +1091,18 @@ impl<'a> Parser<'a> {
     }
 
     fn emit_byte(&mut self, code: OpCode) {
-        self.compiler
-            .function
-            .chunk
-            .write_to_chunk(code, self.previous.line);
+        self.emit_byte_at(code, self.previous.line);
+    }
+
+    // For instructions emitted after their operands were parsed, `line` is the line of the token
+    // which produced the instruction rather than the last token of the operands
+    fn emit_byte_at(&mut self, code: OpCode, line: usize) {
+        self.compiler.function.chunk.write_to_chunk(code, line);
     }
 
-    fn emit_two_bytes(&mut self, code1: OpCode, code2: OpCode) {
-        self.emit_byte(code1);
-        self.emit_byte(code2);
+    fn emit_two_bytes_at(&mut self, code1: OpCode, code2: OpCode, line: usize) {
+        self.emit_byte_at(code1, line);
+        self.emit_byte_at(code2, line);
     }
 
     fn emit_jump(&mut self, code: OpCode) -> usize {
@@ -701,17 +1110,16 @@ impl<'a> Parser<'a> {
         self.current_function_chunk().code.len() - 1
     }
 
-    fn end_compiler(mut self) -> Result<ObjFunction, String> {
+    fn end_compiler(mut self) -> Result<ObjFunction, Vec<String>> {
         self.emit_return();
 
-        if !self.had_error {
-            self.compiler
-                .function
-                .chunk
-                .disassemble_chunk(&self.compiler.function.name.value);
+        if self.errors.is_empty() {
+            if self.optimize {
+                optimize(&mut self.compiler.function.chunk);
+            }
             Ok(self.compiler.function)
         } else {
-            Err("Compile error".to_string())
+            Err(self.errors)
         }
     }
 
@@ -729,10 +1137,31 @@ impl<'a> Parser<'a> {
     // expression statement looks for a semicolon and also emits a pop instruction.
     fn expression_statement(&mut self) {
         self.expression();
+        // the semicolon of the echoed expression is optional, e.g. `1 + 1` in the REPL
+        if self.echo_expression() {
+            return;
+        }
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        if self.echo_expression() {
+            return;
+        }
         self.emit_byte(OpCode::Pop);
     }
 
+    fn echo_expression(&mut self) -> bool {
+        if !self.repl
+            || self.compiler.function_type != FunctionType::Script
+            || self.compiler.scope_depth > 0
+            || !self.check(TokenType::Eof)
+        {
+            return false;
+        }
+
+        self.emit_byte(OpCode::Return);
+        self.echo = true;
+        true
+    }
+
     fn begin_scope(&mut self) {
         self.compiler.scope_depth += 1;
     }
@@ -740,38 +1169,62 @@ impl<'a> Parser<'a> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        // Clear the local variable inside the scope when the scope ends
+        // Clear the local variable inside the scope when the scope ends, a run of locals which are
+        // not captured is popped at once
+        let mut pops = 0;
         while self.compiler.local_count > 0
             && self.compiler.locals[self.compiler.local_count - 1].depth > self.compiler.scope_depth
         {
+            self.warn_if_unused(self.compiler.locals[self.compiler.local_count - 1]);
             if self.compiler.locals[self.compiler.local_count - 1].is_captured {
+                self.emit_pops(pops);
+                pops = 0;
                 self.emit_byte(OpCode::CloseUpvalue);
             } else {
-                self.emit_byte(OpCode::Pop);
+                pops += 1;
             }
             self.compiler.local_count -= 1;
         }
+        self.emit_pops(pops);
     }
 
-    fn block(&mut self) {
+    fn warn_if_unused(&mut self, local: Local) {
+        let name = local.name.lexeme(self.scanner.bytes);
+        if local.used || name.starts_with(b"_") {
+            return;
+        }
+        let msg = format!("Unused variable '{}'.", String::from_utf8_lossy(name));
+        self.warning_at(local.name, &msg);
+    }
+
+    fn emit_pops(&mut self, count: usize) {
+        match count {
+            0 => (),
+            1 => self.emit_byte(OpCode::Pop),
+            _ => self.emit_byte(OpCode::PopN(count)),
+        }
+    }
+
+    // Returns `true` if the block always returns, anything declared after that point is unreachable
+    fn block(&mut self) -> bool {
+        let mut terminates = false;
+        let mut warned = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
-            self.declaration();
+            if terminates && !warned {
+                self.warning_at(self.current, "Unreachable code.");
+                warned = true;
+            }
+            terminates |= self.declaration();
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        terminates
     }
 
     // To handle compiling multiple functions nested within each other, we create a separate
     // compiler for each function being compiled. This compiler is then pushed onto a stack
-    fn function(&mut self, kind: FunctionType) {
-        let compiler = Compiler::new(
-            convert_slice_to_string(
-                self.scanner.bytes,
-                self.previous.start,
-                self.previous.start + self.previous.length,
-            ),
-            kind,
-        );
+    fn function(&mut self, name: String, kind: FunctionType) {
+        let compiler = Compiler::new(name, kind);
         let old_cc = std::mem::replace(&mut self.compiler, compiler);
         // set the enclosing function which is also known as the parent function
         self.compiler.enclosing = Some(Box::new(old_cc));
@@ -780,12 +1233,18 @@ impl<'a> Parser<'a> {
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
         if !self.check(TokenType::RightParen) {
             loop {
-                self.compiler.function.arity += 1;
-                if self.compiler.function.arity == u8::MAX {
-                    self.error_at_current("Cannot have more than 255 parameters.");
+                if self.compiler.function.arity as usize == MAX_PARAMS {
+                    self.error_at_current(&format!(
+                        "Cannot have more than {} parameters.",
+                        MAX_PARAMS
+                    ));
+                } else {
+                    self.compiler.function.arity += 1;
                 }
                 let index = self.variable("Expect parameter name.");
                 self.define_variable(index);
+                // unused parameters are fine, e.g. a callback ignoring some arguments
+                self.compiler.locals[self.compiler.local_count - 1].used = true;
                 if !self.match_token(TokenType::Comma) {
                     break;
                 }
@@ -797,60 +1256,105 @@ impl<'a> Parser<'a> {
 
         self.emit_return();
 
+        // the scope of the body is never ended, its locals go away with the call frame
+        for idx in 0..self.compiler.local_count {
+            self.warn_if_unused(self.compiler.locals[idx]);
+        }
+
         if let Some(new_cc) = self.compiler.enclosing.take() {
-            let function = std::mem::replace(&mut self.compiler, *new_cc).function;
+            let mut function = std::mem::replace(&mut self.compiler, *new_cc).function;
+            if self.optimize {
+                optimize(&mut function.chunk);
+            }
             self.emit_closure(Value::Function(Gc::new(function)));
         }
     }
 
+    // `var a = 1, b = a + 1;` defines each name before the next initializer runs
     fn var_statement(&mut self) {
-        let index = self.variable("Expect variable name.");
-        if self.match_token(TokenType::Equal) {
-            self.expression();
-        } else {
-            self.emit_byte(OpCode::Nil);
+        loop {
+            let index = self.variable("Expect variable name.");
+            if self.match_token(TokenType::Equal) {
+                self.expression();
+            } else {
+                self.emit_byte(OpCode::Nil);
+            }
+            self.define_variable(index);
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
         }
 
         self.consume(
             TokenType::Semicolon,
             "Expect ';' after variable declaration.",
         );
+    }
+
+    // Like `var` but the value is required and can never be assigned again
+    fn const_statement(&mut self) {
+        let index = self.variable("Expect constant name.");
+        if self.compiler.scope_depth > 0 {
+            if self.compiler.local_count > 0 {
+                self.compiler.locals[self.compiler.local_count - 1].is_const = true;
+            }
+        } else {
+            self.const_globals.insert(convert_slice_to_string(
+                self.scanner.bytes,
+                self.previous.start,
+                self.previous.start + self.previous.length,
+            ));
+        }
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
 
         self.define_variable(index);
     }
 
-    fn if_statement(&mut self) {
+    // An if statement only terminates when both branches do
+    fn if_statement(&mut self) -> bool {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
         let jump_idx = self.emit_jump(OpCode::JumpIfFalse(0xff));
         self.emit_byte(OpCode::Pop);
-        self.statement();
+        let then_terminates = self.statement();
 
         let else_jump_idx = self.emit_jump(OpCode::Jump(0xff));
-        self.patch_if_false_jump(jump_idx);
+        self.patch_jump_with(jump_idx, OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop);
 
+        let mut else_terminates = false;
         if self.match_token(TokenType::Else) {
-            self.statement();
+            else_terminates = self.statement();
         }
-        self.patch_jump(else_jump_idx);
+        self.patch_jump_with(else_jump_idx, OpCode::Jump);
+
+        then_terminates && else_terminates
     }
 
     fn while_statement(&mut self) {
-        let loop_start = self.current_function_chunk().code.len() - 1;
+        let loop_start = self.current_function_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
         let jump_idx = self.emit_jump(OpCode::JumpIfFalse(0xff));
         self.emit_byte(OpCode::Pop);
+        self.begin_loop();
         self.statement();
-        self.emit_loop(u16::try_from(loop_start).expect("Chunk code too large"));
+        self.emit_loop(loop_start);
 
-        self.patch_if_false_jump(jump_idx);
+        self.patch_jump_with(jump_idx, OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop);
+        self.end_loop();
     }
 
     fn for_statement(&mut self) {
@@ -866,47 +1370,117 @@ impl<'a> Parser<'a> {
             self.expression_statement();
         }
 
-        let mut jump_idx = 0;
+        // Without a condition there is no exit jump, the loop only ends through `break` or `return`
+        let mut exit_jump = None;
 
         // Condition clause
-        let mut loop_start = self.current_function_chunk().code.len() - 1;
+        let mut loop_start = self.current_function_chunk().code.len();
         if !self.match_token(TokenType::Semicolon) {
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
 
-            jump_idx = self.emit_jump(OpCode::JumpIfFalse(0xff));
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(0xff)));
             self.emit_byte(OpCode::Pop);
         }
 
         // Increment clause
         if !self.match_token(TokenType::RightParen) {
             let body_jump_idx = self.emit_jump(OpCode::Jump(0xff));
-            let increment_start = self.current_function_chunk().code.len() - 1;
+            let increment_start = self.current_function_chunk().code.len();
             self.expression();
             self.emit_byte(OpCode::Pop);
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
 
-            self.emit_loop(u16::try_from(loop_start).expect("Chunk code too large"));
+            self.emit_loop(loop_start);
             loop_start = increment_start;
-            self.patch_jump(body_jump_idx);
+            self.patch_jump_with(body_jump_idx, OpCode::Jump);
         }
+        self.begin_loop();
         self.statement();
-        self.emit_loop(u16::try_from(loop_start).expect("Chunk code too large"));
+        self.emit_loop(loop_start);
 
-        self.patch_if_false_jump(jump_idx);
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump_with(exit_jump, OpCode::JumpIfFalse);
+            // the condition is still on the stack when the loop exits
+            self.emit_byte(OpCode::Pop);
+        }
+        self.end_loop();
         self.end_scope();
     }
 
+    fn begin_loop(&mut self) {
+        self.compiler.loops.push(Loop {
+            scope_depth: self.compiler.scope_depth,
+            breaks: Vec::new(),
+        });
+    }
+
+    // Every `break` of the loop lands right after it
+    fn end_loop(&mut self) {
+        if let Some(finished) = self.compiler.loops.pop() {
+            for jump in finished.breaks {
+                self.patch_jump_with(jump, OpCode::Jump);
+            }
+        }
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        let scope_depth = match self.compiler.loops.last() {
+            Some(current) => current.scope_depth,
+            None => {
+                self.error("Can't use 'break' outside of a loop.");
+                return;
+            }
+        };
+
+        // Locals declared inside the loop body are still on the stack, they are discarded before
+        // jumping out. The compiler keeps tracking them since the scopes themselves do not end here.
+        let mut pops = 0;
+        for idx in (0..self.compiler.local_count).rev() {
+            let local = self.compiler.locals[idx];
+            if local.depth <= scope_depth {
+                break;
+            }
+            if local.is_captured {
+                self.emit_pops(pops);
+                pops = 0;
+                self.emit_byte(OpCode::CloseUpvalue);
+            } else {
+                pops += 1;
+            }
+        }
+        self.emit_pops(pops);
+
+        let jump = self.emit_jump(OpCode::Jump(0xff));
+        if let Some(current) = self.compiler.loops.last_mut() {
+            current.breaks.push(jump);
+        }
+    }
+
+    // An anonymous function expression, e.g. `var f = fun(x) { return x + 1; };`
+    fn lambda(&mut self, _: bool) {
+        self.function(String::from("lambda"), FunctionType::Function);
+    }
+
     fn fun_statement(&mut self, kind: FunctionType) {
         let index = self.variable("Expect function name.");
         self.mark_initialized();
-        self.function(kind);
+        let name = convert_slice_to_string(
+            self.scanner.bytes,
+            self.previous.start,
+            self.previous.start + self.previous.length,
+        );
+        self.function(name, kind);
         self.define_variable(index);
     }
 
     fn return_statement(&mut self) {
+        // Both `return;` and `return x;` are rejected outside of a function, the rest of the
+        // statement is still parsed so it does not cascade into more errors
         if self.compiler.function_type == FunctionType::Script {
-            self.error_at_current("Cannot return a value from an initializer.");
+            self.error("Can't return from top-level code.");
         }
         if self.match_token(TokenType::Semicolon) {
             self.emit_return();
@@ -917,23 +1491,32 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn statement(&mut self) {
+    // Returns `true` if the statement always returns from the current function
+    fn statement(&mut self) -> bool {
         if self.match_token(TokenType::Print) {
             self.print(true);
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
-            self.block();
+            let terminates = self.block();
             self.end_scope();
+            return terminates;
         } else {
             self.expression_statement();
         }
+        false
     }
 
-    fn declaration(&mut self) {
+    // Returns `true` if the declaration always returns from the current function
+    fn declaration(&mut self) -> bool {
+        let mut terminates = false;
         if self.match_token(TokenType::Var) {
             self.var_statement();
+        } else if self.match_token(TokenType::Const) {
+            self.const_statement();
         } else if self.match_token(TokenType::If) {
-            self.if_statement();
+            terminates = self.if_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
         } else if self.match_token(TokenType::For) {
@@ -942,15 +1525,58 @@ impl<'a> Parser<'a> {
             self.fun_statement(FunctionType::Function);
         } else if self.match_token(TokenType::Return) {
             self.return_statement();
+            terminates = true;
         } else {
-            self.statement();
+            terminates = self.statement();
         }
         if self.panic_mode {
             self.synchronize();
         }
+        terminates
     }
 
-    pub fn compile(mut self) -> Result<ObjFunction, String> {
+    // The error holds every reported compile error, in the format they were printed in, e.g.
+    // "[line 1:7] Error at ';': Expect expression."
+    pub fn compile(self) -> Result<ObjFunction, Vec<String>> {
+        self.compile_script(false).map(|script| script.function)
+    }
+
+    // Same as `compile`, the flag is `true` when the script returns the value of its trailing
+    // expression statement
+    pub fn compile_repl(self) -> Result<(ObjFunction, bool), Vec<String>> {
+        self.compile_script(true)
+            .map(|script| (script.function, script.echo))
+    }
+
+    // The vm keeps the constants of every script it compiled, see `const_globals`
+    pub(crate) fn compile_script(mut self, repl: bool) -> Result<Script, Vec<String>> {
+        self.repl = repl;
+        self.compile_declarations();
+        self.report_undefined_globals();
+        let echo = self.echo;
+        let const_globals = std::mem::take(&mut self.const_globals);
+        self.end_compiler().map(|function| Script {
+            function,
+            echo,
+            const_globals,
+        })
+    }
+
+    // Only once the whole script is compiled every global definition is known
+    fn report_undefined_globals(&mut self) {
+        let Some(known) = self.known_globals.take() else {
+            return;
+        };
+        for name in std::mem::take(&mut self.global_uses) {
+            let lexeme =
+                convert_slice_to_string(self.scanner.bytes, name.start, name.start + name.length);
+            if !known.contains(&lexeme) {
+                self.warning_at(name, &format!("Undefined variable '{}'.", lexeme));
+            }
+        }
+    }
+
+    fn compile_declarations(&mut self) {
         self.next_valid_token();
 
         while self.current.t_type != TokenType::Eof {
@@ -958,7 +1584,6 @@ impl<'a> Parser<'a> {
         }
 
         self.consume(TokenType::Eof, "Expect end of expression.");
-        self.end_compiler()
     }
 }
 
@@ -972,6 +1597,18 @@ mod tests {
         assert_eq!(Precedence::Assignment, pre.next())
     }
 
+    #[test]
+    fn test_precedence_or() {
+        let pre = Precedence::Or;
+        assert_eq!(Precedence::Coalesce, pre.next())
+    }
+
+    #[test]
+    fn test_precedence_coalesce() {
+        let pre = Precedence::Coalesce;
+        assert_eq!(Precedence::Xor, pre.next())
+    }
+
     #[test]
     fn test_precedence_term() {
         let pre = Precedence::Term;
@@ -987,6 +1624,12 @@ mod tests {
     #[test]
     fn test_precedence_unary() {
         let pre = Precedence::Unary;
+        assert_eq!(Precedence::Power, pre.next())
+    }
+
+    #[test]
+    fn test_precedence_power() {
+        let pre = Precedence::Power;
         assert_eq!(Precedence::Call, pre.next())
     }
 
@@ -1055,7 +1698,7 @@ mod tests {
     fn test_synchonize() {
         let source = r#"1 + &;"#.as_bytes();
         let parser = Parser::new(source);
-        assert!(!parser.compile().is_ok());
+        assert!(parser.compile().is_err());
     }
 
     #[test]
@@ -1069,6 +1712,38 @@ mod tests {
         assert_eq!(4, obj.as_ref().unwrap().chunk.code.len());
     }
 
+    #[test]
+    fn test_global_constants_dedup() {
+        let source = r#"var a = 1; a = 1; print a + 1;"#.as_bytes();
+        let obj = Parser::new(source).compile().unwrap();
+        // "a" and 1 are stored once each
+        assert_eq!(2, obj.chunk.constants.len());
+    }
+
+    #[test]
+    fn test_interned_identifiers() {
+        let source = r#"
+        var a = "a";
+        fun f() {
+            return a;
+        }
+        "#;
+        let obj = Parser::new(source.as_bytes()).compile().unwrap();
+        let string = |value: &Value| match value {
+            Value::String(s) => s.clone(),
+            _ => panic!("expected a string"),
+        };
+        let global = string(&obj.chunk.constants[0]);
+        let f = match &obj.chunk.constants[2] {
+            Value::Function(f) => f.clone(),
+            _ => panic!("expected a function"),
+        };
+        assert_eq!("f", *string(&obj.chunk.constants[1]));
+        // the name in `f`, the global and the string literal point to the same string
+        assert!(Gc::ptr_eq(&global, &string(&f.chunk.constants[0])));
+        assert_eq!(3, obj.chunk.constants.len());
+    }
+
     #[test]
     fn test_scope() {
         let source = r#"
@@ -1116,7 +1791,7 @@ mod tests {
         "#
         .as_bytes();
         let parser = Parser::new(source);
-        assert!(!parser.compile().is_ok());
+        assert!(parser.compile().is_err());
     }
 
     #[test]
@@ -1168,6 +1843,18 @@ mod tests {
         assert_eq!(14, obj.as_ref().unwrap().chunk.code.len());
     }
 
+    #[test]
+    fn test_chained_comparison() {
+        let parser = Parser::new("print 1 < 2 < 3;".as_bytes());
+        assert!(parser.compile().is_err());
+
+        let parser = Parser::new("print 1 < 2 == 2 > 1;".as_bytes());
+        assert!(parser.compile().is_ok());
+
+        let parser = Parser::new("print 1 < 2 and 2 < 3;".as_bytes());
+        assert!(parser.compile().is_ok());
+    }
+
     #[test]
     fn test_or() {
         let source = r#"
@@ -1182,6 +1869,385 @@ mod tests {
         let obj = parser.compile();
         assert!(obj.is_ok());
         assert_eq!(2, obj.as_ref().unwrap().chunk.constants.len());
+        // `or` needs a single JumpIfTrue
         assert_eq!(14, obj.as_ref().unwrap().chunk.code.len());
+        assert!(obj.unwrap().chunk.code.contains(&OpCode::JumpIfTrue(2)));
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let parser = Parser::new("print nil ?? 5;".as_bytes());
+        let obj = parser.compile().unwrap();
+        assert_eq!(
+            vec![
+                OpCode::Nil,
+                OpCode::JumpIfNotNil(2),
+                OpCode::Pop,
+                OpCode::Constant(0),
+                OpCode::Print,
+                OpCode::Nil,
+                OpCode::Return,
+            ],
+            obj.chunk.code
+        );
+    }
+
+    #[test]
+    fn test_compile_collects_every_error() {
+        // `synchronize` skips to the next statement after each error
+        let source = "var = 1;\nprint 2;\nprint (3;";
+        let errors = Parser::new(source.as_bytes()).compile().unwrap_err();
+        assert_eq!(
+            vec![
+                "[line 1:5] Error at '=': Expect variable name.".to_string(),
+                "[line 3:9] Error at ';': Expect ')' after expression".to_string(),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_end_scope_pop_n() {
+        let source = "{ var a = 1; var b = 2; var c = 3; var d = 4; var e = 5; }";
+        let obj = Parser::new(source.as_bytes()).compile().unwrap();
+        let code = &obj.chunk.code;
+        assert!(code.contains(&OpCode::PopN(5)));
+        assert!(!code.contains(&OpCode::Pop));
+    }
+
+    #[test]
+    fn test_end_scope_keeps_close_upvalue() {
+        let source = "{ var a = 1; var b = 2; fun f() { return b; } var c = 3; var d = 4; }";
+        let obj = Parser::new(source.as_bytes()).compile().unwrap();
+        // d, c, f, then the captured b and at last a
+        assert_eq!(
+            &[
+                OpCode::PopN(3),
+                OpCode::CloseUpvalue,
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Return
+            ],
+            &obj.chunk.code[obj.chunk.code.len() - 5..]
+        );
+    }
+
+    #[test]
+    fn test_const() {
+        let parser = Parser::new("const a = 1; print a;".as_bytes());
+        assert!(parser.compile().is_ok());
+
+        for source in [
+            "const a = 1; a = 2;",
+            "{ const a = 1; a = 2; }",
+            "fun f() { const a = 1; fun g() { a = 2; } }",
+            "const a;",
+        ] {
+            assert!(
+                Parser::new(source.as_bytes()).compile().is_err(),
+                "{}",
+                source
+            );
+        }
+
+        // a local shadowing the constant can be assigned
+        let parser = Parser::new("const a = 1; { var a = 2; a = 3; }".as_bytes());
+        assert!(parser.compile().is_ok());
+    }
+
+    #[test]
+    fn test_unused_local() {
+        // only a warning, the block still compiles
+        let parser = Parser::new("{ var a = 1; var _b = 2; }".as_bytes());
+        assert!(parser.compile().is_ok());
+    }
+
+    #[test]
+    fn test_local_in_own_initializer() {
+        // the half declared inner `x` is found first, it never falls back to the global
+        let parser = Parser::new("var x = 1; { var x = x; }".as_bytes());
+        let errors = parser.compile().unwrap_err();
+        assert_eq!(
+            vec!["[line 1:22] Error at 'x': Can't read local variable in its own initializer."],
+            errors
+        );
+
+        let parser = Parser::new("var x = 1; { var y = x; print y; }".as_bytes());
+        let obj = parser.compile().unwrap();
+        assert!(obj
+            .chunk
+            .code
+            .iter()
+            .any(|code| matches!(code, OpCode::GetGlobal(_))));
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let obj = Parser::new("1 + 2;".as_bytes()).compile().unwrap();
+        assert_eq!(
+            vec![
+                OpCode::Constant(2),
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Return
+            ],
+            obj.chunk.code
+        );
+        assert_eq!(Value::Number(3.0), obj.chunk.constants[2]);
+
+        // -(2 ** 2) + (1 - 3) * 4
+        let obj = Parser::new("print -2 ** 2 + (1 - 3) * 4;".as_bytes())
+            .compile()
+            .unwrap();
+        match obj.chunk.code[0] {
+            OpCode::Constant(index) => assert_eq!(Value::Number(-12.0), obj.chunk.constants[index]),
+            other => panic!("expected a constant, got {:?}", other),
+        }
+        assert_eq!(OpCode::Print, obj.chunk.code[1]);
+    }
+
+    #[test]
+    fn test_constant_folding_needs_literals() {
+        // dividing by zero must still fail at runtime
+        let obj = Parser::new("1 / 0; 1 % 0;".as_bytes()).compile().unwrap();
+        assert!(obj.chunk.code.contains(&OpCode::Divide));
+        assert!(obj.chunk.code.contains(&OpCode::Modulo));
+
+        // the left operand of `+` ends with a constant but isn't one
+        let obj = Parser::new("var a; print (a ?? 1) + 2;".as_bytes())
+            .compile()
+            .unwrap();
+        assert!(obj.chunk.code.contains(&OpCode::JumpIfNotNil(2)));
+        assert!(obj.chunk.code.contains(&OpCode::Add));
+
+        let obj = Parser::new("print \"a\" + \"b\";".as_bytes())
+            .compile()
+            .unwrap();
+        assert!(obj.chunk.code.contains(&OpCode::Add));
+    }
+
+    #[test]
+    fn test_rules() {
+        let parser = Parser::new("".as_bytes());
+        for (idx, token) in TokenType::ALL.into_iter().enumerate() {
+            assert_eq!(idx, token as usize);
+            assert_eq!(token, parser.get_rule(token).token);
+        }
+        assert_eq!(Precedence::No, parser.get_rule(TokenType::Bang).precedence);
+        assert_eq!(
+            Precedence::Power,
+            parser.get_rule(TokenType::StarStar).precedence
+        );
+    }
+
+    #[test]
+    fn test_var_list() {
+        let parser = Parser::new("{ var a = 1, b = a + 1, c; print c; }".as_bytes());
+        let obj = parser.compile().unwrap();
+        assert!(obj.chunk.code.contains(&OpCode::GetLocal(0)));
+        assert!(obj.chunk.code.contains(&OpCode::GetLocal(2)));
+
+        let parser = Parser::new("{ var a = 1, a = 2; }".as_bytes());
+        assert!(parser.compile().is_err());
+    }
+
+    #[test]
+    fn test_top_level_return() {
+        let parser = Parser::new("return;".as_bytes());
+        assert!(parser.compile().is_err());
+
+        let parser = Parser::new("return 1;".as_bytes());
+        assert!(parser.compile().is_err());
+    }
+
+    #[test]
+    fn test_compile_repl_echo() {
+        let parser = Parser::new("var a = 1; a + 1".as_bytes());
+        let (_, echo) = parser.compile_repl().unwrap();
+        assert!(echo);
+
+        let parser = Parser::new("a + 1;".as_bytes());
+        let (_, echo) = parser.compile_repl().unwrap();
+        assert!(echo);
+    }
+
+    #[test]
+    fn test_compile_repl_no_echo() {
+        let parser = Parser::new("var a = 1; print a;".as_bytes());
+        let (_, echo) = parser.compile_repl().unwrap();
+        assert!(!echo);
+
+        let parser = Parser::new("{ 1 + 1; }".as_bytes());
+        let (_, echo) = parser.compile_repl().unwrap();
+        assert!(!echo);
+    }
+
+    #[test]
+    fn test_compile_requires_semicolon() {
+        let parser = Parser::new("1 + 1".as_bytes());
+        assert!(parser.compile().is_err());
+    }
+
+    #[test]
+    fn test_break_outside_loop() {
+        let parser = Parser::new("break;".as_bytes());
+        assert!(parser.compile().is_err());
+    }
+
+    #[test]
+    fn test_for_without_condition_keeps_first_instruction() {
+        let source = r#"
+        var i = 0;
+        for (;;) {
+            break;
+        }
+        "#
+        .as_bytes();
+        let parser = Parser::new(source);
+        let obj = parser.compile().unwrap();
+        assert_eq!(OpCode::Constant(1), obj.chunk.code[0]);
+    }
+
+    #[test]
+    fn test_while_at_start_of_script() {
+        let parser = Parser::new("while (false) {}".as_bytes());
+        let obj = parser.compile().unwrap();
+        // the loop jumps back to the condition, the very first instruction
+        assert_eq!(OpCode::Loop(4), obj.chunk.code[3]);
+    }
+
+    #[test]
+    fn test_for_at_start_of_script() {
+        let parser = Parser::new("for (;;) { break; }".as_bytes());
+        let obj = parser.compile().unwrap();
+        assert_eq!(OpCode::Loop(2), obj.chunk.code[1]);
+    }
+
+    #[test]
+    fn test_large_loop_body() {
+        // every statement is three instructions, far more than 256 in total
+        let body = "a = a + 1;".repeat(200);
+        let source = format!("var a = 0; while (a < 1000) {{ {} }}", body);
+        let parser = Parser::new(source.as_bytes());
+        assert!(parser.compile().is_ok());
+    }
+
+    #[test]
+    fn test_loop_body_too_large() {
+        let body = "a = a + 1;".repeat(20000);
+        let source = format!("var a = 0; while (a < 1000) {{ {} }}", body);
+        let parser = Parser::new(source.as_bytes());
+        assert!(parser.compile().is_err());
+    }
+
+    fn names(count: usize) -> String {
+        (0..count)
+            .map(|idx| format!("a{}", idx))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    #[test]
+    fn test_max_params() {
+        let source = format!("fun f({}) {{}}", names(MAX_PARAMS));
+        let function = Parser::new(source.as_bytes()).compile().unwrap();
+        match &function.chunk.constants[1] {
+            Value::Function(f) => assert_eq!(MAX_PARAMS, f.arity as usize),
+            _ => panic!("expected a function"),
+        }
+
+        let source = format!("fun f({}) {{}}", names(MAX_PARAMS + 1));
+        assert!(Parser::new(source.as_bytes()).compile().is_err());
+    }
+
+    #[test]
+    fn test_max_args() {
+        let args = vec!["nil"; MAX_ARGS].join(", ");
+        let source = format!("f({});", args);
+        assert!(Parser::new(source.as_bytes()).compile().is_ok());
+
+        let source = format!("f({}, nil);", args);
+        assert!(Parser::new(source.as_bytes()).compile().is_err());
+    }
+
+    #[test]
+    fn test_max_locals() {
+        let locals = |count: usize| {
+            (0..count)
+                .map(|idx| format!("var a{};", idx))
+                .collect::<String>()
+        };
+        let source = format!("{{ {} }}", locals(MAX_LOCALS));
+        assert!(Parser::new(source.as_bytes()).compile().is_ok());
+
+        let source = format!("{{ {} }}", locals(MAX_LOCALS + 1));
+        assert!(Parser::new(source.as_bytes()).compile().is_err());
+    }
+
+    #[test]
+    fn test_too_many_upvalues() {
+        // the inner closure captures 150 locals of each enclosing function
+        let locals = |prefix: &str| {
+            (0..150)
+                .map(|idx| format!("var {}{} = {};", prefix, idx, idx))
+                .collect::<String>()
+        };
+        let reads = (0..150)
+            .map(|idx| format!("a{} + b{};", idx, idx))
+            .collect::<String>();
+        let source = format!(
+            "fun outer() {{ {} fun middle() {{ {} fun inner() {{ {} }} }} }}",
+            locals("a"),
+            locals("b"),
+            reads
+        );
+        assert!(Parser::new(source.as_bytes()).compile().is_err());
+    }
+
+    #[test]
+    fn test_upvalue_captured_twice() {
+        let source = r#"
+        fun outer() {
+            var a = 1;
+            var b = 2;
+            fun f() {
+                print b;
+                print b;
+            }
+            fun g() {
+                print a;
+                print b;
+                print b;
+            }
+        }
+        "#;
+        let script = Parser::new(source.as_bytes()).compile().unwrap();
+        let functions = |chunk: &Chunk| {
+            chunk
+                .constants
+                .iter()
+                .filter_map(|value| match value {
+                    Value::Function(f) => Some(f.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let reads = |chunk: &Chunk| {
+            chunk
+                .code
+                .iter()
+                .filter_map(|op| match op {
+                    OpCode::GetUpvalue(slot) => Some(*slot),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let outer = functions(&script.chunk).remove(0);
+        let inner = functions(&outer.chunk);
+        assert_eq!(vec![0, 0], reads(&inner[0].chunk));
+        assert_eq!(1, inner[0].upvalues.len());
+        assert_eq!(vec![0, 1, 1], reads(&inner[1].chunk));
+        assert_eq!(2, inner[1].upvalues.len());
     }
 }
@@ -1,19 +1,18 @@
 #![allow(dead_code)]
 
-use std::fmt::Display;
+use std::fmt::{Debug, Display};
 
 use crate::objects::HashKeyString;
-use crate::value::Value;
 
 const TABLE_MAX_LOAD: f32 = 0.75;
 
 #[derive(PartialEq, Clone)]
-pub struct Entry {
+pub struct Entry<V> {
     key: HashKeyString,
-    value: Value,
+    value: V,
 }
 
-impl Display for Entry {
+impl<V: Debug> Display for Entry<V> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -23,84 +22,172 @@ impl Display for Entry {
     }
 }
 
+// A removed entry leaves a tombstone so the probe sequences running through its slot still find
+// the keys placed after it
 #[derive(PartialEq, Clone)]
-pub struct HashTable {
-    entries: Vec<Entry>,
+enum Slot<V> {
+    Empty,
+    Tombstone,
+    Full(Entry<V>),
+}
+
+// The slot of a key in the table, found with a single probe
+pub enum TableEntry<'a, V> {
+    Occupied(&'a mut V),
+    Vacant(VacantEntry<'a, V>),
+}
+
+pub struct VacantEntry<'a, V> {
+    table: &'a mut HashTable<V>,
+    key: &'a HashKeyString,
+    index: usize,
+}
+
+impl<'a, V> VacantEntry<'a, V> {
+    // The probed slot is only used as long as the table does not have to grow
+    pub fn insert(self, value: V) -> &'a mut V {
+        let table = self.table;
+        let threshold = (table.capacity as f32 * TABLE_MAX_LOAD) as usize;
+        let index = if table.count + table.tombstones + 1 > threshold {
+            let capacity = table.grow_capacity();
+            table.resize(capacity);
+            table.find_entry(self.key).1
+        } else {
+            self.index
+        };
+        if matches!(table.slots[index], Slot::Tombstone) {
+            table.tombstones -= 1;
+        }
+        table.order.push(self.key.clone());
+        table.slots[index] = Slot::Full(Entry {
+            key: self.key.clone(),
+            value,
+        });
+        table.count += 1;
+        match &mut table.slots[index] {
+            Slot::Full(entry) => &mut entry.value,
+            _ => unreachable!("the entry was just inserted"),
+        }
+    }
+}
+
+// Any value can be stored, `nil` included, so a Lox value never doubles as an empty slot
+#[derive(PartialEq, Clone)]
+pub struct HashTable<V> {
+    slots: Vec<Slot<V>>,
     count: usize,
+    // tombstones take up slots until the next resize, the load factor counts them
+    tombstones: usize,
     capacity: usize,
+    // keys in insertion order, the slots move around on resize but iteration stays stable
+    order: Vec<HashKeyString>,
 }
 
-impl HashTable {
+impl<V> HashTable<V> {
     pub fn new() -> Self {
         Self {
-            entries: Vec::new(),
+            slots: Vec::new(),
             count: 0,
+            tombstones: 0,
             capacity: 0,
+            order: Vec::new(),
         }
     }
 
-    pub fn insert(&mut self, key: HashKeyString, value: Value) {
-        let threshold = (self.capacity as f32 * TABLE_MAX_LOAD) as usize;
-        if self.count + 1 > threshold {
-            let capaicty = self.grow_capacity();
-            self.resize(capaicty);
-        }
-        match self.find_entry(&key) {
-            (Some(_), index) => {
-                self.entries[index].value = value;
-            }
-            (None, index) => {
-                let mut element = Entry { key, value };
-                // We want to replace the value, but keep the vec capacity the same.
-                std::mem::swap(&mut self.entries[index], &mut element);
-                self.count += 1;
+    pub fn insert(&mut self, key: HashKeyString, value: V) {
+        match self.entry(&key) {
+            TableEntry::Occupied(slot) => *slot = value,
+            TableEntry::Vacant(entry) => {
+                entry.insert(value);
             }
         }
     }
 
-    fn find_entry(&self, key: &HashKeyString) -> (Option<()>, usize) {
-        let mut index = key.hash as usize % (self.capacity - 1);
-
-        while index < self.capacity {
-            if self.entries[index].value == Value::Nil {
-                return (None, index);
-            } else {
-                let entry = &self.entries[index];
+    // e.g. to assign a global only when it is already defined
+    pub fn entry<'a>(&'a mut self, key: &'a HashKeyString) -> TableEntry<'a, V> {
+        if self.capacity == 0 {
+            // the insert grows the table first, the slot is never used
+            return TableEntry::Vacant(VacantEntry {
+                table: self,
+                key,
+                index: 0,
+            });
+        }
+        match self.find_entry(key) {
+            (Some(_), index) => match &mut self.slots[index] {
+                Slot::Full(entry) => TableEntry::Occupied(&mut entry.value),
+                _ => unreachable!("find_entry found the key"),
+            },
+            (None, index) => TableEntry::Vacant(VacantEntry {
+                table: self,
+                key,
+                index,
+            }),
+        }
+    }
 
-                if entry.key == *key {
-                    return (Some(()), index);
+    // Without the key the index is where it would be inserted, the first tombstone on the way
+    // is reused
+    fn find_entry(&self, key: &HashKeyString) -> (Option<()>, usize) {
+        let mut index = key.hash as usize % self.capacity;
+        let mut tombstone = None;
+
+        loop {
+            match &self.slots[index] {
+                Slot::Empty => return (None, tombstone.unwrap_or(index)),
+                Slot::Tombstone => {
+                    tombstone.get_or_insert(index);
+                }
+                Slot::Full(entry) => {
+                    if entry.key == *key {
+                        return (Some(()), index);
+                    }
                 }
-                index = (index + 1) % self.capacity;
             }
+            index = (index + 1) % self.capacity;
         }
+    }
 
-        (None, index)
+    pub fn get(&self, key: &HashKeyString) -> Option<&V> {
+        if self.count == 0 {
+            return None;
+        }
+        match self.find_entry(key) {
+            (Some(_), index) => match &self.slots[index] {
+                Slot::Full(entry) => Some(&entry.value),
+                _ => None,
+            },
+            (None, _) => None,
+        }
     }
 
-    pub fn get(&self, key: &HashKeyString) -> Option<&Value> {
+    pub fn get_mut(&mut self, key: &HashKeyString) -> Option<&mut V> {
         if self.count == 0 {
             return None;
         }
-        let (found, index) = self.find_entry(key);
-        if found.is_some() {
-            Some(&self.entries[index].value)
-        } else {
-            None
+        match self.find_entry(key) {
+            (Some(_), index) => match &mut self.slots[index] {
+                Slot::Full(entry) => Some(&mut entry.value),
+                _ => None,
+            },
+            (None, _) => None,
         }
     }
 
-    fn remove(&mut self, key: &HashKeyString) -> Option<Value> {
+    fn remove(&mut self, key: &HashKeyString) -> Option<V> {
         if self.count == 0 {
             return None;
         }
         let (found, index) = self.find_entry(key);
-        if found.is_some() {
-            let value = self.entries[index].value.clone();
-            self.entries[index].value = Value::Nil;
-            self.count -= 1;
-            Some(value)
-        } else {
-            None
+        found?;
+        match std::mem::replace(&mut self.slots[index], Slot::Tombstone) {
+            Slot::Full(entry) => {
+                self.count -= 1;
+                self.tombstones += 1;
+                self.order.retain(|k| k != key);
+                Some(entry.value)
+            }
+            _ => unreachable!("find_entry found the key"),
         }
     }
 
@@ -113,26 +200,30 @@ impl HashTable {
     }
 
     fn resize(&mut self, capacity: usize) {
-        let mut entries = Vec::with_capacity(capacity);
-        for _ in 0..capacity {
-            entries.push(Entry {
-                key: HashKeyString {
-                    value: String::new(),
-                    hash: 0,
-                },
-                value: Value::Nil,
-            });
-        }
-
-        for entry in self.entries.iter() {
-            if entry.value != Value::Nil {
-                let index = entry.key.hash as usize % (capacity - 1);
-                entries[index] = entry.clone();
+        let slots = (0..capacity).map(|_| Slot::Empty).collect();
+        let old_slots = std::mem::replace(&mut self.slots, slots);
+        self.capacity = capacity;
+        self.tombstones = 0;
+
+        // Entries are probed into the new slots like a regular insert, placing them directly at
+        // their bucket would overwrite the ones colliding with them
+        for slot in old_slots {
+            if let Slot::Full(entry) = slot {
+                let (_, index) = self.find_entry(&entry.key);
+                self.slots[index] = Slot::Full(entry);
             }
         }
+    }
 
-        self.entries = entries;
-        self.capacity = capacity;
+    // Iterates in insertion order, a key inserted again keeps its first position
+    pub fn iter(&self) -> impl Iterator<Item = (&HashKeyString, &V)> {
+        self.order
+            .iter()
+            .filter_map(|key| self.get(key).map(|value| (key, value)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &HashKeyString> {
+        self.order.iter()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -148,21 +239,29 @@ impl HashTable {
     }
 
     pub fn remove_all(&mut self) {
-        self.entries.clear();
+        self.slots.clear();
         self.count = 0;
+        self.tombstones = 0;
         self.capacity = 0;
+        self.order.clear();
     }
+}
 
+impl<V: Debug + Clone> HashTable<V> {
     fn print(&self) {
-        for entry in self.entries.iter() {
-            if entry.value != Value::Nil {
-                println!("{}", entry);
-            }
+        for (key, value) in self.iter() {
+            println!(
+                "{}",
+                Entry {
+                    key: key.clone(),
+                    value: value.clone()
+                }
+            );
         }
     }
 }
 
-impl Default for HashTable {
+impl<V> Default for HashTable<V> {
     fn default() -> Self {
         Self::new()
     }
@@ -171,6 +270,7 @@ impl Default for HashTable {
 #[cfg(test)]
 mod tests {
     use crate::utils::hash;
+    use crate::value::Value;
 
     use super::*;
 
@@ -262,6 +362,40 @@ mod tests {
         assert_eq!(table.capacity, 16);
     }
 
+    #[test]
+    fn test_hash_table_insertion_order() {
+        let names = [
+            "zeta", "alpha", "mid", "beta", "omega", "a", "z", "k", "q", "last",
+        ];
+        let mut table = HashTable::new();
+        for (i, name) in names.iter().enumerate() {
+            let key = HashKeyString {
+                value: name.to_string(),
+                hash: hash(name),
+            };
+            table.insert(key, Value::Number(i as f64));
+        }
+        // 10 keys went past the first two capacities
+        assert_eq!(table.capacity, 16);
+
+        let key = HashKeyString {
+            value: "alpha".to_string(),
+            hash: hash("alpha"),
+        };
+        table.insert(key.clone(), Value::Number(100.0));
+        let keys: Vec<&str> = table.keys().map(|k| k.value.as_str()).collect();
+        assert_eq!(names.to_vec(), keys);
+
+        let values: Vec<Value> = table.iter().map(|(_, v)| v.clone()).collect();
+        assert_eq!(Value::Number(0.0), values[0]);
+        assert_eq!(Value::Number(100.0), values[1]);
+
+        table.remove(&key);
+        let keys: Vec<&str> = table.keys().map(|k| k.value.as_str()).collect();
+        assert_eq!(9, keys.len());
+        assert_eq!(["zeta", "mid"], keys[..2]);
+    }
+
     #[test]
     fn test_hash_table_get() {
         let mut table = HashTable::new();
@@ -281,6 +415,142 @@ mod tests {
         assert_eq!(value, Some(&Value::Number(1.0)));
     }
 
+    #[test]
+    fn test_hash_table_get_mut() {
+        let mut table = HashTable::new();
+        let key = HashKeyString {
+            value: "hello".to_string(),
+            hash: hash("hello"),
+        };
+        assert_eq!(table.get_mut(&key), None);
+        table.insert(key.clone(), Value::Number(1.0));
+        *table.get_mut(&key).unwrap() = Value::Number(2.0);
+        assert_eq!(table.get(&key), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_hash_table_entry() {
+        let mut table = HashTable::new();
+        let key = HashKeyString {
+            value: "hello".to_string(),
+            hash: hash("hello"),
+        };
+        match table.entry(&key) {
+            TableEntry::Occupied(_) => panic!("the table is empty"),
+            TableEntry::Vacant(entry) => *entry.insert(Value::Number(1.0)) = Value::Number(2.0),
+        }
+        assert_eq!(table.get(&key), Some(&Value::Number(2.0)));
+
+        match table.entry(&key) {
+            TableEntry::Occupied(slot) => *slot = Value::Number(3.0),
+            TableEntry::Vacant(_) => panic!("the key was inserted"),
+        }
+        assert_eq!(table.get(&key), Some(&Value::Number(3.0)));
+        assert_eq!(table.count, 1);
+        assert_eq!(vec![&key], table.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_hash_table_entry_insert_resize() {
+        let mut table = HashTable::new();
+        let keys: Vec<HashKeyString> = (0..7)
+            .map(|idx| {
+                let name = format!("key{}", idx);
+                HashKeyString {
+                    hash: hash(&name),
+                    value: name,
+                }
+            })
+            .collect();
+        for (idx, key) in keys.iter().enumerate() {
+            match table.entry(key) {
+                TableEntry::Occupied(_) => panic!("every key is new"),
+                TableEntry::Vacant(entry) => {
+                    entry.insert(Value::Number(idx as f64));
+                }
+            }
+        }
+        // the 7th key went past the load factor of 8 slots
+        assert_eq!(table.capacity, 16);
+        for (idx, key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(&Value::Number(idx as f64)));
+        }
+    }
+
+    #[test]
+    fn test_hash_table_nil_value() {
+        let mut table = HashTable::new();
+        let key = HashKeyString {
+            value: "hello".to_string(),
+            hash: hash("hello"),
+        };
+        table.insert(key.clone(), Value::Nil);
+        assert_eq!(table.get(&key), Some(&Value::Nil));
+        assert_eq!(table.count, 1);
+
+        // a nil value is not an empty slot, inserting again updates it
+        table.insert(key.clone(), Value::Number(1.0));
+        assert_eq!(table.count, 1);
+        assert_eq!(table.remove(&key), Some(Value::Number(1.0)));
+        assert_eq!(table.get(&key), None);
+    }
+
+    #[test]
+    fn test_hash_table_tombstone_keeps_probe_sequence() {
+        let mut table = HashTable::new();
+        let keys: Vec<HashKeyString> = (0..5)
+            .map(|idx| HashKeyString {
+                value: format!("key{}", idx),
+                // every key starts probing at the same slot
+                hash: 3,
+            })
+            .collect();
+        for (idx, key) in keys.iter().enumerate() {
+            table.insert(key.clone(), idx);
+        }
+        assert_eq!(table.remove(&keys[1]), Some(1));
+        // the keys placed after the removed one are still found
+        for (idx, key) in keys.iter().enumerate().skip(2) {
+            assert_eq!(table.get(key), Some(&idx));
+        }
+        // the tombstone is reused
+        table.insert(keys[1].clone(), 10);
+        assert_eq!(table.tombstones, 0);
+        assert_eq!(table.get(&keys[1]), Some(&10));
+        assert_eq!(table.count, 5);
+    }
+
+    #[test]
+    fn test_hash_table_resize_keeps_colliding_keys() {
+        let mut table = HashTable::new();
+        // the hashes are equal modulo 8 and 16, every key collides before and after growing
+        let keys: Vec<HashKeyString> = (0..12)
+            .map(|idx| HashKeyString {
+                value: format!("key{}", idx),
+                hash: 5 + 32 * idx as u64,
+            })
+            .collect();
+        for (idx, key) in keys.iter().enumerate() {
+            table.insert(key.clone(), Value::Number(idx as f64));
+        }
+        assert_eq!(table.capacity, 16);
+        assert_eq!(table.count, 12);
+        for (idx, key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(&Value::Number(idx as f64)));
+        }
+    }
+
+    #[test]
+    fn test_hash_table_uses_last_bucket() {
+        let mut table = HashTable::new();
+        let key = HashKeyString {
+            value: "last".to_string(),
+            hash: 7,
+        };
+        table.insert(key.clone(), 1);
+        assert_eq!((Some(()), 7), table.find_entry(&key));
+    }
+
     #[test]
     fn test_hash_table_get_not_found() {
         let mut table = HashTable::new();
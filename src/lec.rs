@@ -1,8 +1,8 @@
 use std::alloc::{alloc, dealloc, handle_alloc_error, realloc, Layout};
 use std::marker::PhantomData;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::ptr::{self, NonNull};
-use std::{isize, mem};
 
 /*
 We need an array of bytes, since we don't know how big the array needs to be before we start
@@ -19,6 +19,9 @@ pub struct Vec<T> {
     _maker: PhantomData<T>,
 }
 
+// The name the book uses, so `rox::lec::Lec` reads like the notes
+pub type Lec<T> = Vec<T>;
+
 unsafe impl<T: Send> Send for Vec<T> {}
 unsafe impl<T: Sync> Sync for Vec<T> {}
 
@@ -35,16 +38,27 @@ impl<T> Vec<T> {
         }
     }
 
+    // Allocates room for `capacity` elements up front, `push` does not grow until it is used up
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut vec = Self::new();
+        if capacity > 0 {
+            vec.grow_to(capacity);
+        }
+        vec
+    }
+
     pub fn grow(&mut self) {
-        let (new_cap, new_layout) = if self.cap == 0 {
-            (1, Layout::array::<T>(1).expect("Unable to get layout"))
+        let new_cap = if self.cap == 0 {
+            1
         } else {
             // this can't overflow since self.cap <= isize.Max.
-            let new_cap = 2 * self.cap;
-
-            let new_layout = Layout::array::<T>(new_cap).expect("Unable to get layout");
-            (new_cap, new_layout)
+            2 * self.cap
         };
+        self.grow_to(new_cap);
+    }
+
+    fn grow_to(&mut self, new_cap: usize) {
+        let new_layout = Layout::array::<T>(new_cap).expect("Unable to get layout");
 
         assert!(
             new_layout.size() <= isize::MAX as usize,
@@ -102,6 +116,110 @@ impl<T> Vec<T> {
             unsafe { Some(ptr::read(self.ptr.as_ptr().add(self.len))) }
         }
     }
+
+    // Shifts every element from `index` on one slot to the right, `index == len` appends
+    pub fn insert(&mut self, index: usize, value: T) {
+        assert!(index <= self.len, "index out of bounds");
+        if self.len == self.cap {
+            self.grow();
+        }
+
+        unsafe {
+            let at = self.ptr.as_ptr().add(index);
+            // the ranges overlap, copy is memmove
+            ptr::copy(at, at.add(1), self.len - index);
+            ptr::write(at, value);
+        }
+
+        self.len += 1;
+    }
+
+    // Shifts every element after `index` one slot to the left
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "index out of bounds");
+
+        unsafe {
+            self.len -= 1;
+            let at = self.ptr.as_ptr().add(index);
+            let value = ptr::read(at);
+            ptr::copy(at.add(1), at, self.len - index);
+            value
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < self.len {
+            unsafe { Some(&*self.ptr.as_ptr().add(index)) }
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index < self.len {
+            unsafe { Some(&mut *self.ptr.as_ptr().add(index)) }
+        } else {
+            None
+        }
+    }
+
+    // Pushes every element of `iter`, growing the same way `push` does
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+// Moves the elements out front to back, the ones left when the iterator is dropped are dropped
+// with it
+pub struct IntoIter<T> {
+    vec: Vec<T>,
+    next: usize,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.next == self.vec.len {
+            None
+        } else {
+            let value = unsafe { ptr::read(self.vec.ptr.as_ptr().add(self.next)) };
+            self.next += 1;
+            Some(value)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.vec.len - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T> Drop for IntoIter<T> {
+    fn drop(&mut self) {
+        for _ in &mut *self {}
+        // every element has been moved out, the vec only frees its buffer
+        self.vec.len = 0;
+    }
+}
+
+impl<T> IntoIterator for Vec<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { vec: self, next: 0 }
+    }
+}
+
+impl<T> FromIterator<T> for Vec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut vec = Vec::new();
+        vec.extend(iter);
+        vec
+    }
 }
 
 impl<T> Drop for Vec<T> {
@@ -116,6 +234,21 @@ impl<T> Drop for Vec<T> {
     }
 }
 
+// The clone gets a buffer of the same capacity, an empty vec with no buffer stays unallocated
+impl<T: Clone> Clone for Vec<T> {
+    fn clone(&self) -> Self {
+        let mut vec = Self::with_capacity(self.cap);
+        for value in self.iter() {
+            unsafe {
+                ptr::write(vec.ptr.as_ptr().add(vec.len), value.clone());
+            }
+            // bumped one at a time so a panicking clone only drops what was written
+            vec.len += 1;
+        }
+        vec
+    }
+}
+
 impl<T> Default for Vec<T> {
     fn default() -> Self {
         Self::new()
@@ -169,4 +302,130 @@ mod tests {
         assert_eq!(2, lec.len());
         assert_eq!(4, lec.capacity());
     }
+
+    #[test]
+    fn with_capacity() {
+        let mut lec: Vec<u8> = Vec::with_capacity(3);
+        assert_eq!(0, lec.len());
+        assert_eq!(3, lec.capacity());
+        lec.push(1);
+        lec.push(2);
+        lec.push(3);
+        assert_eq!(3, lec.capacity());
+        lec.push(4);
+        assert_eq!(6, lec.capacity());
+
+        let empty: Vec<u8> = Vec::with_capacity(0);
+        assert_eq!(0, empty.capacity());
+    }
+
+    #[test]
+    fn from_iter_and_extend() {
+        let mut lec: Vec<u32> = (0..10).collect();
+        assert_eq!(10, lec.len());
+        assert_eq!(16, lec.capacity());
+        assert_eq!(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9], &lec[..]);
+
+        lec.extend(10..20);
+        assert_eq!(20, lec.len());
+        assert_eq!(32, lec.capacity());
+        assert_eq!(19, lec[19]);
+    }
+
+    #[test]
+    fn insert() {
+        let mut lec: Vec<u8> = (1..4).collect();
+        lec.insert(1, 10);
+        assert_eq!(&[1, 10, 2, 3], &lec[..]);
+        lec.insert(0, 0);
+        lec.insert(5, 4);
+        assert_eq!(&[0, 1, 10, 2, 3, 4], &lec[..]);
+        assert_eq!(6, lec.len());
+        assert_eq!(8, lec.capacity());
+
+        let mut empty: Vec<u8> = Vec::new();
+        empty.insert(0, 1);
+        assert_eq!(&[1], &empty[..]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut lec: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!("a", lec.remove(0));
+        assert_eq!(2, lec.len());
+        assert_eq!("c", lec.remove(1));
+        assert_eq!(&[String::from("b")], &lec[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn insert_out_of_range() {
+        let mut lec: Vec<u8> = Vec::new();
+        lec.insert(1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn remove_out_of_range() {
+        let mut lec: Vec<u8> = (0..2).collect();
+        lec.remove(2);
+    }
+
+    #[test]
+    fn clone() {
+        let mut lec: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let copy = lec.clone();
+        lec[0].push('!');
+        lec.push(String::from("d"));
+        assert_eq!(&["a", "b", "c"], &copy[..]);
+        assert_eq!(4, copy.capacity());
+        assert_eq!("a!", lec[0]);
+
+        let empty: Vec<u8> = Vec::new();
+        assert_eq!(0, empty.clone().capacity());
+    }
+
+    #[test]
+    fn get() {
+        let mut lec: Lec<u8> = (1..4).collect();
+        assert_eq!(Some(&1), lec.get(0));
+        assert_eq!(Some(&3), lec.get(2));
+        assert_eq!(None, lec.get(3));
+        assert_eq!(None, Vec::<u8>::new().get(0));
+
+        *lec.get_mut(1).unwrap() = 20;
+        lec[2] = 30;
+        assert_eq!(&[1, 20, 30], &lec[..]);
+        assert_eq!(None, lec.get_mut(3));
+    }
+
+    #[test]
+    fn into_iter() {
+        let lec: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let mut iter = lec.into_iter();
+        assert_eq!((3, Some(3)), iter.size_hint());
+        assert_eq!(Some(String::from("a")), iter.next());
+        let rest: std::vec::Vec<String> = iter.collect();
+        assert_eq!(vec![String::from("b"), String::from("c")], rest);
+    }
+
+    #[test]
+    fn into_iter_drops_the_rest() {
+        use std::rc::Rc;
+
+        let value = Rc::new(1);
+        let lec: Vec<Rc<i32>> = (0..3).map(|_| value.clone()).collect();
+        let mut iter = lec.into_iter();
+        let first = iter.next();
+        drop(iter);
+        assert_eq!(2, Rc::strong_count(&value));
+        drop(first);
+        assert_eq!(1, Rc::strong_count(&value));
+    }
+
+    #[test]
+    #[should_panic(expected = "We're not ready to handle ZSTs")]
+    fn from_iter_zst() {
+        let _: Vec<()> = std::iter::empty().collect();
+    }
 }
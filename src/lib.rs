@@ -2,8 +2,10 @@ pub mod chunk;
 pub mod compiler;
 pub mod hashtable;
 pub mod lec;
+pub mod limits;
 pub mod objects;
 pub mod op_code;
+pub mod optimizer;
 pub mod scanner;
 pub mod stack;
 pub mod token;
@@ -0,0 +1,19 @@
+// The limits of the compiler and the vm in one place.
+// `Call` and `Closure` take `usize` operands, only the arity of a function is stored in a `u8`.
+
+// Arguments of a single call, `f(a1, ..., a255)` compiles and one more is an error
+pub const MAX_ARGS: usize = 255;
+// Parameters of a function, it must fit `ObjFunction::arity`
+pub const MAX_PARAMS: usize = u8::MAX as usize;
+// Locals of a function, parameters included
+pub const MAX_LOCALS: usize = 256;
+// Variables a single closure captures
+pub const MAX_UPVALUES: usize = 256;
+
+// Nested calls before the vm reports a stack overflow
+pub const FRAME_MAX: usize = 64;
+// Leaves every frame room for 256 slots
+pub const STACK_MAX: usize = 256 * FRAME_MAX;
+
+// Bytes the collector allocates before its first collection, it grows when little is freed
+pub const GC_THRESHOLD: usize = 1024 * 1024;
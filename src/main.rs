@@ -4,56 +4,173 @@ use std::{
     process::exit,
 };
 
-use rox::vm::{InterpretError, Vm};
+use rox::{
+    limits::GC_THRESHOLD,
+    scanner::is_incomplete,
+    vm::{InterpretError, Vm},
+};
 
-fn main() {
-    let mut vm = Vm::new();
-    vm.initialize();
+// The exit codes of rox, they follow BSD sysexits.h
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExitCode {
+    Success = 0,
+    // An interpret error which is neither a compile nor a runtime error
+    Default = 2,
+    // The command was used incorrectly, e.g. with the wrong number of arguments
+    UsageError = 64,
+    // The script could not be compiled
+    CompileError = 65,
+    // The script failed while running
+    RuntimeError = 70,
+    // Reading the input failed
+    IoError = 74,
+}
+
+impl From<InterpretError> for ExitCode {
+    fn from(error: InterpretError) -> Self {
+        match error {
+            InterpretError::Default => ExitCode::Default,
+            InterpretError::CompileError(_) => ExitCode::CompileError,
+            InterpretError::RuntimeError(_) => ExitCode::RuntimeError,
+        }
+    }
+}
+
+fn exit_with(code: ExitCode) -> ! {
+    exit(code as i32)
+}
 
-    let args: Vec<String> = env::args().collect();
+const USAGE: &str = "Usage: rox [options] [script]
 
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]),
-        _ => {
-            println!("rox can not recognize arguments");
-            exit(64)
+Without a script rox starts a REPL.
+
+Options:
+    --dump-bytecode    Print the bytecode of the script instead of running it
+    -h, --help         Print this help
+    -V, --version      Print the version of rox";
+
+// What rox was asked to do by its arguments
+#[derive(Debug, PartialEq, Eq)]
+enum Mode {
+    Repl,
+    Run(String),
+    DumpBytecode(String),
+    Help,
+    Version,
+}
+
+// `args` excludes the program name, e.g. `rox --dump-bytecode a.lox` gives
+// `["--dump-bytecode", "a.lox"]`. The error is the reason shown above the usage.
+fn parse_args(args: &[String]) -> Result<Mode, String> {
+    let mut dump_bytecode = false;
+    let mut script = None;
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Mode::Help),
+            "-V" | "--version" => return Ok(Mode::Version),
+            "--dump-bytecode" => dump_bytecode = true,
+            flag if flag.starts_with('-') => return Err(format!("unknown option '{}'", flag)),
+            _ if script.is_some() => return Err("only one script can be run".to_string()),
+            file_name => script = Some(file_name.to_string()),
         }
     }
+
+    match (script, dump_bytecode) {
+        (Some(file_name), true) => Ok(Mode::DumpBytecode(file_name)),
+        (Some(file_name), false) => Ok(Mode::Run(file_name)),
+        (None, true) => Err("--dump-bytecode needs a script".to_string()),
+        (None, false) => Ok(Mode::Repl),
+    }
 }
 
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mode = match parse_args(&args) {
+        Ok(mode) => mode,
+        Err(reason) => {
+            eprintln!("rox: {}\n\n{}", reason, USAGE);
+            exit_with(ExitCode::UsageError)
+        }
+    };
+
+    // the collector's own default collects after every few bytes
+    let mut vm = Vm::with_gc_threshold(GC_THRESHOLD);
+    vm.initialize();
+
+    match mode {
+        Mode::Repl => repl(&mut vm),
+        Mode::Run(file_name) => run_file(&mut vm, &file_name),
+        Mode::DumpBytecode(file_name) => dump_bytecode(&vm, &file_name),
+        Mode::Help => println!("{}", USAGE),
+        Mode::Version => println!("rox {}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+// The REPL keeps running after a compile or runtime error, the error has already been reported by
+// the vm so we only reset it and prompt again. Only EOF ends the session.
+// An incomplete statement, e.g. a function whose body is still open, keeps reading lines with a
+// continuation prompt until the input can be compiled.
+// When the input ends with an expression statement its value is echoed, e.g. `1 + 1;` prints 2.
 fn repl(vm: &mut Vm) {
+    let mut input = String::new();
     loop {
-        print!("> ");
+        if input.is_empty() {
+            print!("> ");
+        } else {
+            print!("... ");
+        }
         io::stdout().flush().expect("Can't flush stdout");
-        let mut input = String::new();
-        if let Err(e) = io::stdin().read_line(&mut input) {
-            print!("{}", e);
-            exit(74)
+        let mut line = String::new();
+        if let Err(e) = io::stdin().read_line(&mut line) {
+            eprintln!("{}", e);
+            exit_with(ExitCode::IoError)
         }
-        if input.is_empty() {
+        if line.is_empty() {
             break;
         }
 
-        if let Err(e) = vm.interpret(&input) {
-            match e {
-                InterpretError::Default => exit(2),
-                InterpretError::RuntimeError => exit(70),
-                InterpretError::CompileError => exit(65),
-            }
+        input.push_str(&line);
+        if is_incomplete(input.as_bytes()) {
+            continue;
+        }
+
+        match vm.interpret_repl(&input) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => (),
+            Err(_) => vm.reset(),
+        }
+        input.clear();
+    }
+}
+
+// The content is not checked for UTF-8 here, the compiler reports invalid bytes
+fn read_file(file_name: &str) -> Vec<u8> {
+    match std::fs::read(file_name) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Could not read file {}: {}", file_name, e);
+            exit_with(ExitCode::IoError)
         }
     }
 }
 
 fn run_file(vm: &mut Vm, file_name: &str) {
-    let content = std::fs::read(file_name).expect("Could not read file");
-    let input = String::from_utf8(content).expect("Could not convert file to string");
-    match vm.interpret(&input) {
-        Ok(_) => exit(0),
-        Err(error) => match error {
-            InterpretError::Default => exit(2),
-            InterpretError::RuntimeError => exit(70),
-            InterpretError::CompileError => exit(65),
-        },
+    let input = read_file(file_name);
+    match vm.interpret_bytes(&input) {
+        Ok(_) => exit_with(ExitCode::Success),
+        Err(error) => exit_with(error.into()),
+    }
+}
+
+// `rox --dump-bytecode file.lox` prints the bytecode of the script and of every function in it,
+// the script is never run
+fn dump_bytecode(vm: &Vm, file_name: &str) {
+    let input = read_file(file_name);
+    match vm.compile(&input) {
+        Ok(function) => {
+            function.disassemble();
+            exit_with(ExitCode::Success)
+        }
+        Err(error) => exit_with(error.into()),
     }
 }
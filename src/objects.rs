@@ -3,7 +3,8 @@ use std::fmt;
 use crate::{chunk::Chunk, utils::hash, value::Value};
 use gc_derive::{Finalize, Trace};
 use rox_gc::{Gc, GcCell};
-pub const MAX_UPVALUES: usize = 256;
+
+use crate::limits::MAX_UPVALUES;
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone, PartialOrd, Trace, Finalize)]
 pub struct HashKeyString {
@@ -57,6 +58,13 @@ pub struct ObjFunction {
     pub upvalues: Vec<UpValue>,
 }
 
+// e.g. `<fn add/2>`, closures print the function they wrap
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}/{}>", self.name.value, self.arity)
+    }
+}
+
 impl ObjFunction {
     pub fn new(name: String) -> Self {
         Self {
@@ -69,6 +77,16 @@ impl ObjFunction {
             upvalues: Vec::with_capacity(MAX_UPVALUES),
         }
     }
+
+    // Disassembles this function, then every function declared in it
+    pub fn disassemble(&self) {
+        self.chunk.disassemble_chunk(&self.name.value);
+        for constant in self.chunk.constants.iter() {
+            if let Value::Function(function) = constant {
+                function.disassemble();
+            }
+        }
+    }
 }
 
 // Define a new type for closures.
@@ -90,12 +108,15 @@ impl ObjClosure {
     }
 }
 
+// A native function gets the call arguments, an error message is reported as a runtime error
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
 // Define a new type for native functions
 #[derive(Clone, Trace, Finalize)]
 pub struct ObjNative {
     pub name: HashKeyString,
     #[unsafe_ignore_trace]
-    pub func: fn(&[Value]) -> Value,
+    pub func: NativeFn,
 }
 
 // Impl below traits because we have a function pointer in ObjNative
@@ -118,7 +139,7 @@ impl PartialEq for ObjNative {
 }
 
 impl ObjNative {
-    pub fn new(name: String, function: fn(&[Value]) -> Value) -> Self {
+    pub fn new(name: String, function: NativeFn) -> Self {
         Self {
             name: HashKeyString {
                 hash: hash(&name),
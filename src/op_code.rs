@@ -4,6 +4,8 @@ use std::fmt::Display;
 #[derive(PartialEq, PartialOrd, Eq, Debug, Clone, Copy)]
 pub enum OpCode {
     Add,
+    // Pops the given number of values off the stack and pushes a list holding them
+    BuildList(usize),
     Call(usize),
     Closure(usize),
     // Different than Pop, it is needed because the compiler needs to hoist the variable out of the
@@ -14,7 +16,6 @@ pub enum OpCode {
     Equal,
     False,
     DefineGlobal(usize),
-    DefineLocal,
     SetGlobal(usize),
     GetGlobal(usize),
     SetLocal(usize),
@@ -22,27 +23,115 @@ pub enum OpCode {
     SetUpvalue(usize),
     GetUpvalue(usize),
     Greater,
+    // Computed as `!(a < b)`, the optimizer folds `Less Not` into it
+    GreaterEqual,
+    // Compares two values by identity, heap objects are only identical when they are the same object
+    Identical,
+    // Pops an index and a list, pushes the element at that index
+    Index,
+    // Pops a value, an index and a list, stores the value at that index and pushes it back
+    IndexSet,
     Less,
+    // Computed as `!(a > b)`, the optimizer folds `Greater Not` into it
+    LessEqual,
     Loop(u16),
     Jump(u16),
+    // Jumps when the top of the stack is falsey. The value is left on the stack so `and` and `or`
+    // can keep it as their result, the compiler emits the Pop on every path which drops it.
     JumpIfFalse(u16),
+    // Jumps when the top of the stack is truthy, the value is left on the stack like JumpIfFalse
+    JumpIfTrue(u16),
+    // Jumps when the top of the stack is not nil, the value is left on the stack
+    JumpIfNotNil(u16),
+    // Pops the divisor and the dividend, the remainder has the sign of the dividend like f64's `%`
+    Modulo,
     Nil,
     Not,
+    NotEqual,
     Multiply,
     Negative,
-    Placeholder,
     // When a local variable goes out of scope, the compiler emits a Pop instruction to remove it
     Pop,
+    // Pops the given number of values at once
+    PopN(usize),
+    // Unary `+`, leaves a number as it is and fails on anything else
+    Positive,
+    // Pops the exponent and the base, pushes the base raised to the exponent
+    Power,
     Print,
+    // Prints the given number of values on one line, separated by spaces
+    PrintMulti(usize),
     Return,
+    // Same as `Nil` followed by `Return`
+    ReturnNil,
     Subtract,
     True,
+    // Pops two values, pushes true when exactly one of them is truthy
+    Xor,
+}
+
+impl OpCode {
+    // Net change of the stack depth once the instruction ran, e.g. `Add` pops two values and
+    // pushes one. `Return` only counts the popped return value, the frame itself is discarded by
+    // the vm.
+    pub fn stack_effect(&self) -> i32 {
+        match self {
+            Self::Constant(_)
+            | Self::Closure(_)
+            | Self::False
+            | Self::True
+            | Self::Nil
+            | Self::GetGlobal(_)
+            | Self::GetLocal(_)
+            | Self::GetUpvalue(_) => 1,
+            Self::Add
+            | Self::Subtract
+            | Self::Multiply
+            | Self::Divide
+            | Self::Modulo
+            | Self::Power
+            | Self::Equal
+            | Self::NotEqual
+            | Self::Xor
+            | Self::Greater
+            | Self::GreaterEqual
+            | Self::Less
+            | Self::LessEqual
+            | Self::Identical
+            | Self::Index
+            | Self::CloseUpvalue
+            | Self::DefineGlobal(_)
+            | Self::Pop
+            | Self::Print
+            | Self::Return => -1,
+            Self::IndexSet => -2,
+            // the set instructions peek, the assigned value stays on the stack
+            Self::SetGlobal(_)
+            | Self::SetLocal(_)
+            | Self::SetUpvalue(_)
+            | Self::Jump(_)
+            | Self::JumpIfFalse(_)
+            | Self::JumpIfTrue(_)
+            | Self::JumpIfNotNil(_)
+            | Self::Loop(_)
+            | Self::Not
+            | Self::Negative
+            | Self::Positive
+            | Self::ReturnNil => 0,
+            // pops the elements, pushes the list
+            Self::BuildList(count) => 1 - *count as i32,
+            // pops the callee and its arguments, pushes the result
+            Self::Call(arg_count) => -(*arg_count as i32),
+            Self::PopN(count) | Self::PrintMulti(count) => -(*count as i32),
+        }
+    }
 }
 
 impl Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Add => write!(f, "add operation"),
+            Self::BuildList(v) => write!(f, "build list of {} elements", v),
             Self::Call(v) => write!(f, "system call {}", v),
             Self::Closure(v) => write!(f, "closure {}", v),
             Self::CloseUpvalue => write!(f, "close upvalue"),
@@ -55,24 +144,115 @@ impl Display for OpCode {
             Self::SetLocal(v) => write!(f, "set local variable in stack from index {}", v),
             Self::SetUpvalue(v) => write!(f, "set upvalue from index {}", v),
             Self::GetUpvalue(v) => write!(f, "get upvalue from index {}", v),
-            Self::DefineLocal => write!(f, "define local variable"),
             Self::GetGlobal(v) => write!(f, "get global variable from index {}", v),
             Self::SetGlobal(v) => write!(f, "set global variable from index {}", v),
             Self::Greater => write!(f, "greater operation"),
+            Self::GreaterEqual => write!(f, "greater equal operation"),
+            Self::Identical => write!(f, "identical operation"),
+            Self::Index => write!(f, "index operation"),
+            Self::IndexSet => write!(f, "index set operation"),
             Self::Less => write!(f, "less operation"),
+            Self::LessEqual => write!(f, "less equal operation"),
             Self::Loop(v) => write!(f, "loop to offset {}", v),
             Self::Jump(v) => write!(f, "jump to {}", v),
             Self::JumpIfFalse(v) => write!(f, "jump to offset {}", v),
+            Self::JumpIfTrue(v) => write!(f, "jump to offset {}", v),
+            Self::JumpIfNotNil(v) => write!(f, "jump to offset {}", v),
+            Self::Modulo => write!(f, "modulo operation"),
             Self::Multiply => write!(f, "multiply operation"),
             Self::Negative => write!(f, "negative operation"),
             Self::Nil => write!(f, "nil"),
             Self::Not => write!(f, "not operation"),
-            Self::Placeholder => write!(f, "placeholder"),
+            Self::NotEqual => write!(f, "not equal operation"),
             Self::Pop => write!(f, "pop operation"),
+            Self::PopN(v) => write!(f, "pop {} values", v),
+            Self::Positive => write!(f, "positive operation"),
+            Self::Power => write!(f, "power operation"),
             Self::Print => write!(f, "print operation"),
+            Self::PrintMulti(v) => write!(f, "print {} values", v),
             Self::Return => write!(f, "system return"),
+            Self::ReturnNil => write!(f, "system return nil"),
             Self::Subtract => write!(f, "subtract operation"),
             Self::True => write!(f, "true"),
+            Self::Xor => write!(f, "xor operation"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Parser;
+
+    #[test]
+    fn test_stack_effect() {
+        let effects = [
+            (OpCode::Add, -1),
+            (OpCode::BuildList(0), 1),
+            (OpCode::BuildList(3), -2),
+            (OpCode::Call(0), 0),
+            (OpCode::Call(2), -2),
+            (OpCode::Closure(0), 1),
+            (OpCode::CloseUpvalue, -1),
+            (OpCode::Constant(0), 1),
+            (OpCode::Divide, -1),
+            (OpCode::Equal, -1),
+            (OpCode::False, 1),
+            (OpCode::DefineGlobal(0), -1),
+            (OpCode::SetGlobal(0), 0),
+            (OpCode::GetGlobal(0), 1),
+            (OpCode::SetLocal(0), 0),
+            (OpCode::GetLocal(0), 1),
+            (OpCode::SetUpvalue(0), 0),
+            (OpCode::GetUpvalue(0), 1),
+            (OpCode::Greater, -1),
+            (OpCode::GreaterEqual, -1),
+            (OpCode::Identical, -1),
+            (OpCode::Index, -1),
+            (OpCode::IndexSet, -2),
+            (OpCode::Less, -1),
+            (OpCode::LessEqual, -1),
+            (OpCode::Loop(1), 0),
+            (OpCode::Jump(1), 0),
+            (OpCode::JumpIfFalse(1), 0),
+            (OpCode::JumpIfTrue(1), 0),
+            (OpCode::JumpIfNotNil(1), 0),
+            (OpCode::Modulo, -1),
+            (OpCode::Nil, 1),
+            (OpCode::Not, 0),
+            (OpCode::NotEqual, -1),
+            (OpCode::Multiply, -1),
+            (OpCode::Negative, 0),
+            (OpCode::Pop, -1),
+            (OpCode::PopN(3), -3),
+            (OpCode::Positive, 0),
+            (OpCode::Power, -1),
+            (OpCode::Print, -1),
+            (OpCode::PrintMulti(3), -3),
+            (OpCode::Return, -1),
+            (OpCode::ReturnNil, 0),
+            (OpCode::Subtract, -1),
+            (OpCode::True, 1),
+            (OpCode::Xor, -1),
+        ];
+        for (instruction, effect) in effects {
+            assert_eq!(effect, instruction.stack_effect(), "{:?}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_stack_effect_of_compiled_script() {
+        let source = r#"
+        var a = 1;
+        {
+            var b = [a, 2];
+            b[0] = -a * 3;
+            print b[0] + 1;
         }
+        "#;
+        let function = Parser::new(source.as_bytes()).compile().unwrap();
+        let net: i32 = function.chunk.code.iter().map(OpCode::stack_effect).sum();
+        // straight line code leaves the stack as it found it
+        assert_eq!(0, net);
     }
 }
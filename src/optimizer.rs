@@ -0,0 +1,435 @@
+use std::collections::HashSet;
+
+use crate::chunk::Chunk;
+use crate::op_code::OpCode;
+use crate::value::Value;
+
+// Peephole pass over a compiled chunk, it collapses:
+//  Equal Not / Greater Not / Less Not -> NotEqual / LessEqual / GreaterEqual
+//  Nil Return / Constant(nil) Return  -> ReturnNil
+//  Pop Pop ...                        -> PopN
+//  Constant Pop / Jump(0)             -> removed
+//  Negative Negative / Not Not        -> removed, only when the value before them is already a
+//                                        number / bool so no runtime error or conversion is lost
+// A `Pop` after `Print` is never redundant, `Print` already consumes its operand so the `Pop`
+// discards another value and is kept.
+// Instructions which are the target of a jump are never folded into the previous instruction,
+// every jump offset is recomputed and `lines` keeps the line of the first folded instruction.
+pub fn optimize(chunk: &mut Chunk) {
+    let targets = jump_targets(&chunk.code);
+    let mut code = Vec::with_capacity(chunk.code.len());
+    let mut lines = Vec::with_capacity(chunk.lines.len());
+    // the new position of every old instruction, plus one for the end of the chunk. A removed
+    // instruction maps to the instruction after it.
+    let mut new_index = Vec::with_capacity(chunk.code.len() + 1);
+    // the old position of every new instruction
+    let mut origin = Vec::with_capacity(chunk.code.len());
+
+    let mut idx = 0;
+    while idx < chunk.code.len() {
+        let (instruction, width) = rewrite(chunk, idx, &targets);
+        for _ in 0..width {
+            new_index.push(code.len());
+        }
+        if let Some(instruction) = instruction {
+            code.push(instruction);
+            lines.push(chunk.lines[idx]);
+            origin.push(idx);
+        }
+        idx += width;
+    }
+    new_index.push(code.len());
+
+    // Jumps are never folded, a kept jump is still the same instruction
+    for (at, &old) in origin.iter().enumerate() {
+        match &chunk.code[old] {
+            OpCode::Jump(offset) => {
+                let target = new_index[old + 1 + *offset as usize];
+                code[at] = OpCode::Jump((target - at - 1) as u16);
+            }
+            OpCode::JumpIfFalse(offset) => {
+                let target = new_index[old + 1 + *offset as usize];
+                code[at] = OpCode::JumpIfFalse((target - at - 1) as u16);
+            }
+            OpCode::JumpIfTrue(offset) => {
+                let target = new_index[old + 1 + *offset as usize];
+                code[at] = OpCode::JumpIfTrue((target - at - 1) as u16);
+            }
+            OpCode::JumpIfNotNil(offset) => {
+                let target = new_index[old + 1 + *offset as usize];
+                code[at] = OpCode::JumpIfNotNil((target - at - 1) as u16);
+            }
+            OpCode::Loop(offset) => {
+                let target = new_index[old + 1 - *offset as usize];
+                code[at] = OpCode::Loop((at + 1 - target) as u16);
+            }
+            _ => (),
+        }
+    }
+
+    chunk.code = code;
+    chunk.lines = lines;
+}
+
+// Returns the folded instruction starting at `idx`, or `None` when they are removed, and how many
+// instructions it replaces
+fn rewrite(chunk: &Chunk, idx: usize, targets: &HashSet<usize>) -> (Option<OpCode>, usize) {
+    let next = |distance: usize| {
+        if targets.contains(&(idx + distance)) {
+            None
+        } else {
+            chunk.code.get(idx + distance).copied()
+        }
+    };
+
+    // only known when nothing jumps in between
+    let previous = if idx == 0 || targets.contains(&idx) {
+        None
+    } else {
+        Some(chunk.code[idx - 1])
+    };
+
+    match (chunk.code[idx], next(1)) {
+        (OpCode::Equal, Some(OpCode::Not)) => (Some(OpCode::NotEqual), 2),
+        (OpCode::Greater, Some(OpCode::Not)) => (Some(OpCode::LessEqual), 2),
+        (OpCode::Less, Some(OpCode::Not)) => (Some(OpCode::GreaterEqual), 2),
+        (OpCode::Nil, Some(OpCode::Return)) => (Some(OpCode::ReturnNil), 2),
+        (OpCode::Constant(index), Some(OpCode::Return)) if chunk.constants[index] == Value::Nil => {
+            (Some(OpCode::ReturnNil), 2)
+        }
+        (OpCode::Constant(_), Some(OpCode::Pop)) => (None, 2),
+        (OpCode::Jump(0), _) => (None, 1),
+        (OpCode::Negative, Some(OpCode::Negative))
+            if previous.is_some_and(|code| produces_number(chunk, code)) =>
+        {
+            (None, 2)
+        }
+        (OpCode::Not, Some(OpCode::Not)) if previous.is_some_and(produces_bool) => (None, 2),
+        (OpCode::Pop, Some(OpCode::Pop)) => {
+            let mut count = 2;
+            while next(count) == Some(OpCode::Pop) {
+                count += 1;
+            }
+            (Some(OpCode::PopN(count)), count)
+        }
+        (instruction, _) => (Some(instruction), 1),
+    }
+}
+
+// Whether the value the instruction pushes is always a number, if it doesn't fail
+fn produces_number(chunk: &Chunk, instruction: OpCode) -> bool {
+    match instruction {
+        OpCode::Constant(index) => matches!(chunk.constants[index], Value::Number(_)),
+        OpCode::Negative
+        | OpCode::Positive
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Modulo
+        | OpCode::Power => true,
+        _ => false,
+    }
+}
+
+// Whether the value the instruction pushes is always a bool
+fn produces_bool(instruction: OpCode) -> bool {
+    matches!(
+        instruction,
+        OpCode::True
+            | OpCode::False
+            | OpCode::Not
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Identical
+            | OpCode::Xor
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+    )
+}
+
+// Every instruction index some jump lands on, the end of the chunk included
+fn jump_targets(code: &[OpCode]) -> HashSet<usize> {
+    let mut targets = HashSet::new();
+    for (idx, instruction) in code.iter().enumerate() {
+        match instruction {
+            OpCode::Jump(offset)
+            | OpCode::JumpIfFalse(offset)
+            | OpCode::JumpIfTrue(offset)
+            | OpCode::JumpIfNotNil(offset) => {
+                targets.insert(idx + 1 + *offset as usize);
+            }
+            OpCode::Loop(offset) => {
+                targets.insert(idx + 1 - *offset as usize);
+            }
+            _ => (),
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Parser;
+
+    // Returns the size of the script chunk before and after optimization
+    fn sizes(source: &str) -> (usize, usize) {
+        let plain = Parser::new(source.as_bytes()).compile().unwrap();
+        let mut parser = Parser::new(source.as_bytes());
+        parser.set_optimize(true);
+        let optimized = parser.compile().unwrap();
+        assert_eq!(optimized.chunk.code.len(), optimized.chunk.lines.len());
+        (plain.chunk.code.len(), optimized.chunk.code.len())
+    }
+
+    #[test]
+    fn test_optimize_empty_script() {
+        // Nil, Return
+        assert_eq!((2, 1), sizes(""));
+    }
+
+    #[test]
+    fn test_optimize_comparisons() {
+        // each of `!=`, `<=` and `>=` drops its Not
+        assert_eq!((17, 13), sizes("print 1 != 2; print 1 <= 2; print 1 >= 2;"));
+    }
+
+    #[test]
+    fn test_optimize_scope_pops() {
+        let source = r#"
+        {
+            var a = 1;
+            var b = 2;
+            var c = 3;
+        }
+        "#;
+        // the compiler already pops the scope at once
+        // Constant x3, PopN(3), Nil, Return -> Constant x3, PopN(3), ReturnNil
+        assert_eq!((6, 5), sizes(source));
+    }
+
+    #[test]
+    fn test_optimize_keeps_jump_targets() {
+        // the Pop after the else jump is the target of JumpIfFalse, it must not be folded
+        let source = r#"
+        if (1 <= 2) {
+            print "yes";
+        }
+        "#;
+        let mut parser = Parser::new(source.as_bytes());
+        parser.set_optimize(true);
+        let function = parser.compile().unwrap();
+        assert_eq!(
+            vec![
+                OpCode::Constant(0),
+                OpCode::Constant(1),
+                OpCode::LessEqual,
+                OpCode::JumpIfFalse(4),
+                OpCode::Pop,
+                OpCode::Constant(2),
+                OpCode::Print,
+                OpCode::Jump(1),
+                OpCode::Pop,
+                OpCode::ReturnNil,
+            ],
+            function.chunk.code
+        );
+    }
+
+    #[test]
+    fn test_optimize_function() {
+        let source = r#"
+        fun f(a, b) {
+            return a >= b;
+        }
+        "#;
+        let plain = Parser::new(source.as_bytes()).compile().unwrap();
+        let mut parser = Parser::new(source.as_bytes());
+        parser.set_optimize(true);
+        let optimized = parser.compile().unwrap();
+
+        let body = |chunk: &Chunk| match &chunk.constants[1] {
+            Value::Function(f) => f.chunk.code.len(),
+            _ => panic!("expected a function"),
+        };
+        // GetLocal, GetLocal, Less, Not, Return, Nil, Return
+        assert_eq!(7, body(&plain.chunk));
+        assert_eq!(5, body(&optimized.chunk));
+    }
+
+    fn optimized(instructions: &[OpCode], constants: &[Value]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for constant in constants {
+            chunk.constants.push(constant.clone());
+        }
+        for (line, instruction) in instructions.iter().enumerate() {
+            chunk.write_to_chunk(*instruction, line + 1);
+        }
+        optimize(&mut chunk);
+        assert_eq!(chunk.code.len(), chunk.lines.len());
+        chunk
+    }
+
+    #[test]
+    fn test_optimize_constant_pop() {
+        let chunk = optimized(
+            &[
+                OpCode::Constant(0),
+                OpCode::Pop,
+                OpCode::True,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            &[Value::Number(1.0)],
+        );
+        assert_eq!(
+            vec![OpCode::True, OpCode::Print, OpCode::ReturnNil],
+            chunk.code
+        );
+        assert_eq!(vec![3, 4, 5], chunk.lines);
+    }
+
+    #[test]
+    fn test_optimize_jump_to_next() {
+        // 0: True 1: JumpIfFalse(2) 2: Jump(0) 3: Nil 4: Print 5: ReturnNil
+        let chunk = optimized(
+            &[
+                OpCode::True,
+                OpCode::JumpIfFalse(2),
+                OpCode::Jump(0),
+                OpCode::Nil,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            &[],
+        );
+        // the JumpIfFalse still lands on Print
+        assert_eq!(
+            vec![
+                OpCode::True,
+                OpCode::JumpIfFalse(1),
+                OpCode::Nil,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            chunk.code
+        );
+    }
+
+    #[test]
+    fn test_optimize_double_negative() {
+        let chunk = optimized(
+            &[
+                OpCode::Constant(0),
+                OpCode::Negative,
+                OpCode::Negative,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            &[Value::Number(1.0)],
+        );
+        assert_eq!(
+            vec![OpCode::Constant(0), OpCode::Print, OpCode::ReturnNil],
+            chunk.code
+        );
+
+        // `- -nil` fails at runtime, the negations stay
+        let chunk = optimized(
+            &[
+                OpCode::Nil,
+                OpCode::Negative,
+                OpCode::Negative,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            &[],
+        );
+        assert_eq!(5, chunk.code.len());
+    }
+
+    #[test]
+    fn test_optimize_double_not() {
+        let chunk = optimized(
+            &[
+                OpCode::True,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            &[],
+        );
+        assert_eq!(
+            vec![OpCode::True, OpCode::Print, OpCode::ReturnNil],
+            chunk.code
+        );
+
+        // `!!nil` is false, not nil
+        let chunk = optimized(
+            &[
+                OpCode::Nil,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::ReturnNil,
+            ],
+            &[],
+        );
+        assert_eq!(5, chunk.code.len());
+    }
+
+    #[test]
+    fn test_optimize_keeps_pair_after_jump_target() {
+        // 0: Nil 1: JumpIfFalse(1) 2: True 3: Not 4: Not 5: ReturnNil
+        // the jump lands on 3 with nil on the stack, so the Nots are kept
+        let chunk = optimized(
+            &[
+                OpCode::Nil,
+                OpCode::JumpIfFalse(1),
+                OpCode::True,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::ReturnNil,
+            ],
+            &[],
+        );
+        assert_eq!(6, chunk.code.len());
+    }
+
+    #[test]
+    fn test_optimize_loop_offsets() {
+        let mut chunk = Chunk::new();
+        // 0: Nil 1: Pop 2: Pop 3: True 4: JumpIfFalse(2) 5: Pop 6: Loop(4) 7: Pop 8: Nil 9: Return
+        for instruction in [
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::True,
+            OpCode::JumpIfFalse(2),
+            OpCode::Pop,
+            OpCode::Loop(4),
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ] {
+            chunk.write_to_chunk(instruction, 1);
+        }
+        optimize(&mut chunk);
+        assert_eq!(
+            vec![
+                OpCode::Nil,
+                OpCode::PopN(2),
+                OpCode::True,
+                OpCode::JumpIfFalse(2),
+                OpCode::Pop,
+                OpCode::Loop(4),
+                OpCode::Pop,
+                OpCode::ReturnNil,
+            ],
+            chunk.code
+        );
+        assert_eq!(8, chunk.lines.len());
+    }
+}
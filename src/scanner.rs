@@ -7,7 +7,9 @@ lazy_static! {
     static ref KEYWORDS: HashMap<String, TokenType> = {
         let mut m = HashMap::new();
         m.insert(String::from("and"), TokenType::And);
+        m.insert(String::from("break"), TokenType::Break);
         m.insert(String::from("class"), TokenType::Class);
+        m.insert(String::from("const"), TokenType::Const);
         m.insert(String::from("else"), TokenType::Else);
         m.insert(String::from("false"), TokenType::False);
         m.insert(String::from("for"), TokenType::For);
@@ -22,6 +24,7 @@ lazy_static! {
         m.insert(String::from("true"), TokenType::True);
         m.insert(String::from("var"), TokenType::Var);
         m.insert(String::from("while"), TokenType::While);
+        m.insert(String::from("xor"), TokenType::Xor);
         m
     };
 }
@@ -31,6 +34,11 @@ pub struct Scanner<'bytes> {
     start: usize,
     current: usize,
     line: usize,
+    // where the current line begins, columns are counted from it
+    line_start: usize,
+    // the position of the token being scanned, a multi-line string is reported where it starts
+    start_line: usize,
+    column: usize,
 }
 
 impl<'bytes> Scanner<'bytes> {
@@ -40,59 +48,82 @@ impl<'bytes> Scanner<'bytes> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            column: 1,
         }
     }
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.column = self.start - self.line_start + 1;
 
         if self.is_end() {
             return self.make_token(TokenType::Eof);
         }
 
-        let c = self.next();
+        let c = self.advance();
 
         match c {
             b'(' => self.make_token(TokenType::LeftParen),
             b')' => self.make_token(TokenType::RightParen),
             b'{' => self.make_token(TokenType::LeftBrace),
             b'}' => self.make_token(TokenType::RightBrace),
+            b'[' => self.make_token(TokenType::LeftBracket),
+            b']' => self.make_token(TokenType::RightBracket),
             b',' => self.make_token(TokenType::Comma),
             b'.' => self.make_token(TokenType::Dot),
             b'-' => self.make_token(TokenType::Minus),
             b'+' => self.make_token(TokenType::Plus),
             b';' => self.make_token(TokenType::Semicolon),
-            b'*' => self.make_token(TokenType::Star),
+            b'*' => {
+                if self.match_type(b'*') {
+                    self.make_token(TokenType::StarStar)
+                } else {
+                    self.make_token(TokenType::Star)
+                }
+            }
             b'/' => self.make_token(TokenType::Slash),
+            b'%' => self.make_token(TokenType::Percent),
+            b'?' if self.match_type(b'?') => self.make_token(TokenType::QuestionQuestion),
             b'!' => {
-                if let true = self.match_type(b'=') {
+                if self.match_type(b'=') {
                     self.make_token(TokenType::BangEqual)
                 } else {
                     self.make_token(TokenType::Bang)
                 }
             }
             b'=' => {
-                if let true = self.match_type(b'=') {
-                    self.make_token(TokenType::EqualEqual)
+                if self.match_type(b'=') {
+                    if self.match_type(b'=') {
+                        self.make_token(TokenType::EqualEqualEqual)
+                    } else {
+                        self.make_token(TokenType::EqualEqual)
+                    }
                 } else {
                     self.make_token(TokenType::Equal)
                 }
             }
             b'<' => {
-                if let true = self.match_type(b'=') {
+                if self.match_type(b'=') {
                     self.make_token(TokenType::LessEqual)
                 } else {
                     self.make_token(TokenType::Less)
                 }
             }
             b'>' => {
-                if let true = self.match_type(b'=') {
+                if self.match_type(b'=') {
                     self.make_token(TokenType::GreaterEqual)
                 } else {
                     self.make_token(TokenType::Greater)
                 }
             }
             b'"' => self.string(),
+            b'r' if self.peek() == b'"' => {
+                self.advance();
+                self.raw_string()
+            }
             c if is_digit(c) => self.number(),
             c if is_alphabet(c) => self.identifier(),
             _ => self.make_token(TokenType::Error),
@@ -104,20 +135,19 @@ impl<'bytes> Scanner<'bytes> {
             t_type,
             start: self.start,
             length: self.current - self.start,
-            line: self.line,
+            line: self.start_line,
+            column: self.column,
+            end_line: self.line,
+            end_column: self.current - self.line_start + 1,
         }
     }
 
-    fn error_token(&self, message: &str) -> Token {
-        Token {
-            t_type: TokenType::Error,
-            start: self.start,
-            length: message.len(),
-            line: self.line,
-        }
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
     }
 
-    fn next(&mut self) -> u8 {
+    fn advance(&mut self) -> u8 {
         self.current += 1;
         self.bytes[self.current - 1]
     }
@@ -137,17 +167,20 @@ impl<'bytes> Scanner<'bytes> {
     fn skip_whitespace(&mut self) {
         while !self.is_end() {
             match self.peek() {
-                b' ' | b'\r' | b'\t' => {
-                    self.next();
+                b' ' | b'\t' => {
+                    self.advance();
                 }
-                b'\n' => {
-                    self.next();
-                    self.line += 1;
+                b'\r' | b'\n' => {
+                    let line_break = self.is_line_break();
+                    self.advance();
+                    if line_break {
+                        self.new_line();
+                    }
                 }
                 b'/' => {
                     if self.peek_next() == b'/' {
-                        while self.peek() != b'\n' || self.is_end() {
-                            self.next();
+                        while self.peek() != b'\n' && self.peek() != b'\r' && !self.is_end() {
+                            self.advance();
                         }
                     } else {
                         break;
@@ -158,15 +191,24 @@ impl<'bytes> Scanner<'bytes> {
         }
     }
 
+    // `\n` and a lone `\r` (old Mac) break a line, `\r\n` counts once because only its `\n` does
+    fn is_line_break(&self) -> bool {
+        match self.peek() {
+            b'\n' => true,
+            b'\r' => self.peek_next() != b'\n',
+            _ => false,
+        }
+    }
+
     fn number(&mut self) -> Token {
         while is_digit(self.peek()) {
-            self.next();
+            self.advance();
         }
 
         if self.peek() == b'.' && is_digit(self.peek_next()) {
-            self.next();
+            self.advance();
             while is_digit(self.peek()) {
-                self.next();
+                self.advance();
             }
         }
 
@@ -175,7 +217,7 @@ impl<'bytes> Scanner<'bytes> {
 
     fn identifier(&mut self) -> Token {
         while is_alphabet(self.peek()) || is_digit(self.peek()) {
-            self.next();
+            self.advance();
         }
 
         let identifier = self
@@ -192,21 +234,45 @@ impl<'bytes> Scanner<'bytes> {
 
     fn string(&mut self) -> Token {
         while self.peek() != b'"' && !self.is_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
+            // an escaped character never closes the string, the compiler decodes the escape
+            if self.peek() == b'\\' && self.peek_next() != b'\0' {
+                self.advance();
+            }
+            let line_break = self.is_line_break();
+            self.advance();
+            if line_break {
+                self.new_line();
             }
-            self.next();
         }
 
+        // the error token covers the whole unterminated string, starting at its quote
         if self.is_end() {
-            return self.error_token("Unterminated string");
+            return self.make_token(TokenType::Error);
         }
 
         // Locate the closing quote.
-        self.next();
+        self.advance();
         self.make_token(TokenType::Strings)
     }
 
+    // Like `string` but nothing is escaped, so a raw string ends at the first quote
+    fn raw_string(&mut self) -> Token {
+        while self.peek() != b'"' && !self.is_end() {
+            let line_break = self.is_line_break();
+            self.advance();
+            if line_break {
+                self.new_line();
+            }
+        }
+
+        if self.is_end() {
+            return self.make_token(TokenType::Error);
+        }
+
+        self.advance();
+        self.make_token(TokenType::RawStrings)
+    }
+
     fn peek_next(&self) -> u8 {
         if self.is_end() || self.current + 1 >= self.bytes.len() {
             b'\0'
@@ -224,6 +290,76 @@ impl<'bytes> Scanner<'bytes> {
     }
 }
 
+// Yields every token up to but excluding `Eof`, scan errors come out as `Error` tokens
+impl<'bytes> Iterator for Scanner<'bytes> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.scan_token();
+        if token.t_type == TokenType::Eof {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
+
+// Every token of the source without compiling it, `Eof` is not included.
+// Use `Token::lexeme` for the text of a token and `error_message` for why it is an `Error`.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    Scanner::new(source.as_bytes()).collect()
+}
+
+// Why the scanner produced an `Error` token, `None` for every other token
+pub fn error_message(source: &[u8], token: &Token) -> Option<String> {
+    if token.t_type != TokenType::Error {
+        return None;
+    }
+
+    let lexeme = token.lexeme(source);
+    if is_string_start(lexeme) {
+        Some(String::from("Unterminated string."))
+    } else {
+        // the byte may be a part of a multi-byte character or not UTF-8 at all
+        Some(format!(
+            "Unexpected character '{}'.",
+            String::from_utf8_lossy(lexeme)
+        ))
+    }
+}
+
+// The REPL keeps reading lines while the input is incomplete, which means a brace or paren is still
+// open, a string is unterminated, or the last token can not end a statement.
+pub fn is_incomplete(source: &[u8]) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0;
+    let mut last = TokenType::Eof;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.t_type {
+            TokenType::LeftBrace | TokenType::LeftParen | TokenType::LeftBracket => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen | TokenType::RightBracket => depth -= 1,
+            TokenType::Error if is_string_start(&source[token.start..]) => return true,
+            TokenType::Eof => break,
+            _ => (),
+        }
+        last = token.t_type;
+    }
+
+    if last == TokenType::Eof {
+        // Nothing but whitespace and comments
+        return false;
+    }
+
+    depth > 0 || !matches!(last, TokenType::Semicolon | TokenType::RightBrace)
+}
+
+// Whether the lexeme opens a string or a raw string
+pub fn is_string_start(lexeme: &[u8]) -> bool {
+    lexeme.starts_with(b"\"") || lexeme.starts_with(b"r\"")
+}
+
 fn is_digit(c: u8) -> bool {
     c.is_ascii_digit()
 }
@@ -237,11 +373,95 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_next() {
+    fn test_advance() {
         let mut scanner = Scanner::new("abc".as_bytes());
-        assert_eq!(b'a', scanner.next());
-        assert_eq!(b'b', scanner.next());
-        assert_eq!(b'c', scanner.next());
+        assert_eq!(b'a', scanner.advance());
+        assert_eq!(b'b', scanner.advance());
+        assert_eq!(b'c', scanner.advance());
+    }
+
+    #[test]
+    fn test_iterator() {
+        let types: Vec<TokenType> = Scanner::new("var a = 1;".as_bytes())
+            .map(|token| token.t_type)
+            .collect();
+        assert_eq!(
+            vec![
+                TokenType::Var,
+                TokenType::Identifier,
+                TokenType::Equal,
+                TokenType::Number,
+                TokenType::Semicolon,
+            ],
+            types
+        );
+
+        let mut scanner = Scanner::new("".as_bytes());
+        assert!(scanner.next().is_none());
+        assert!(scanner.next().is_none());
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let source = "fun f() { return \"a\"; } #";
+        let tokens = tokenize(source);
+        assert_eq!(10, tokens.len());
+        assert_eq!(
+            vec![
+                TokenType::Fun,
+                TokenType::Identifier,
+                TokenType::LeftParen,
+                TokenType::RightParen,
+                TokenType::LeftBrace,
+                TokenType::Return,
+                TokenType::Strings,
+                TokenType::Semicolon,
+                TokenType::RightBrace,
+                TokenType::Error,
+            ],
+            tokens.iter().map(|token| token.t_type).collect::<Vec<_>>()
+        );
+
+        assert_eq!(b"f", tokens[1].lexeme(source.as_bytes()));
+        assert_eq!(b"\"a\"", tokens[6].lexeme(source.as_bytes()));
+        assert_eq!(None, error_message(source.as_bytes(), &tokens[6]));
+        assert_eq!(
+            Some(String::from("Unexpected character '#'.")),
+            error_message(source.as_bytes(), &tokens[9])
+        );
+
+        let tokens = tokenize("\"abc");
+        assert_eq!(
+            Some(String::from("Unterminated string.")),
+            error_message("\"abc".as_bytes(), &tokens[0])
+        );
+    }
+
+    #[test]
+    fn test_span() {
+        let source = "a != bc\n  ident";
+        let tokens = tokenize(source);
+
+        assert_eq!((2, 4), tokens[1].span());
+        assert_eq!(b"!=", tokens[1].lexeme(source.as_bytes()));
+        assert_eq!((1, 3), tokens[1].start_position());
+        assert_eq!((1, 5), tokens[1].end_position());
+
+        assert_eq!((5, 7), tokens[2].span());
+        assert_eq!((1, 8), tokens[2].end_position());
+
+        assert_eq!((10, 15), tokens[3].span());
+        assert_eq!((2, 3), tokens[3].start_position());
+        assert_eq!((2, 8), tokens[3].end_position());
+    }
+
+    #[test]
+    fn test_span_multi_line_string() {
+        let tokens = tokenize("\"ab\ncd\" x");
+        assert_eq!((0, 7), tokens[0].span());
+        assert_eq!((1, 1), tokens[0].start_position());
+        assert_eq!((2, 4), tokens[0].end_position());
+        assert_eq!((2, 5), tokens[1].start_position());
     }
 
     #[test]
@@ -249,7 +469,7 @@ mod tests {
         let mut scanner = Scanner::new("abc".as_bytes());
         assert_eq!(b'a', scanner.peek());
         assert_eq!(b'a', scanner.peek());
-        scanner.next();
+        scanner.advance();
         assert_eq!(b'b', scanner.peek());
         assert_eq!(b'b', scanner.peek());
     }
@@ -259,7 +479,7 @@ mod tests {
         let mut scanner = Scanner::new("abc".as_bytes());
         assert_eq!(b'b', scanner.peek_next());
         assert_eq!(b'b', scanner.peek_next());
-        scanner.next();
+        scanner.advance();
         assert_eq!(b'c', scanner.peek_next());
         assert_eq!(b'c', scanner.peek_next());
     }
@@ -277,9 +497,9 @@ mod tests {
     fn test_is_end() {
         let mut scanner = Scanner::new("abc".as_bytes());
         assert!(!scanner.is_end());
-        scanner.next();
-        scanner.next();
-        scanner.next();
+        scanner.advance();
+        scanner.advance();
+        scanner.advance();
         assert!(scanner.is_end());
     }
 
@@ -287,9 +507,9 @@ mod tests {
     fn test_skip_whitespace() {
         let mut scanner = Scanner::new("a   c".as_bytes());
         assert!(!scanner.is_end());
-        scanner.next();
+        scanner.advance();
         scanner.skip_whitespace();
-        scanner.next();
+        scanner.advance();
         assert!(scanner.is_end());
     }
 
@@ -311,6 +531,22 @@ mod tests {
         assert_eq!(TokenType::Strings, scanner.scan_token().t_type);
     }
 
+    #[test]
+    fn test_raw_string() {
+        let mut scanner = Scanner::new(r#"r"a\" rb"#.as_bytes());
+        let token = scanner.scan_token();
+        assert_eq!(TokenType::RawStrings, token.t_type);
+        assert_eq!(5, token.length);
+        assert_eq!(TokenType::Identifier, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_raw_string_multiline() {
+        let mut scanner = Scanner::new("r\"a\nb\" c".as_bytes());
+        assert_eq!(TokenType::RawStrings, scanner.scan_token().t_type);
+        assert_eq!(2, scanner.scan_token().line);
+    }
+
     #[test]
     fn test_bool() {
         let mut scanner = Scanner::new("true false".as_bytes());
@@ -330,6 +566,12 @@ mod tests {
         assert_eq!(TokenType::And, scanner.scan_token().t_type);
     }
 
+    #[test]
+    fn test_break() {
+        let mut scanner = Scanner::new("break".as_bytes());
+        assert_eq!(TokenType::Break, scanner.scan_token().t_type);
+    }
+
     #[test]
     fn test_or() {
         let mut scanner = Scanner::new("or".as_bytes());
@@ -348,6 +590,12 @@ mod tests {
         assert_eq!(TokenType::EqualEqual, scanner.scan_token().t_type);
     }
 
+    #[test]
+    fn test_identical() {
+        let mut scanner = Scanner::new("===".as_bytes());
+        assert_eq!(TokenType::EqualEqualEqual, scanner.scan_token().t_type);
+    }
+
     #[test]
     fn test_not_equal() {
         let mut scanner = Scanner::new("!=".as_bytes());
@@ -401,4 +649,162 @@ mod tests {
         let mut scanner = Scanner::new("}".as_bytes());
         assert_eq!(TokenType::RightBrace, scanner.scan_token().t_type);
     }
+
+    #[test]
+    fn test_left_bracket() {
+        let mut scanner = Scanner::new("[".as_bytes());
+        assert_eq!(TokenType::LeftBracket, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_right_bracket() {
+        let mut scanner = Scanner::new("]".as_bytes());
+        assert_eq!(TokenType::RightBracket, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_const() {
+        let mut scanner = Scanner::new("const".as_bytes());
+        assert_eq!(TokenType::Const, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_question_question() {
+        let mut scanner = Scanner::new("??".as_bytes());
+        assert_eq!(TokenType::QuestionQuestion, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_percent() {
+        let mut scanner = Scanner::new("%".as_bytes());
+        assert_eq!(TokenType::Percent, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_comment_at_end() {
+        let mut scanner = Scanner::new("// comment".as_bytes());
+        assert_eq!(TokenType::Eof, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_is_incomplete_balanced() {
+        assert!(!is_incomplete("print 1;".as_bytes()));
+        assert!(!is_incomplete("fun foo() { print 1; }".as_bytes()));
+        assert!(!is_incomplete("".as_bytes()));
+        assert!(!is_incomplete("// comment\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_is_incomplete_open_brace() {
+        assert!(is_incomplete("fun foo() {\n".as_bytes()));
+        assert!(is_incomplete("fun foo() {\n  if (true) {}\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_is_incomplete_open_paren() {
+        assert!(is_incomplete("print (1 +\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_is_incomplete_missing_semicolon() {
+        assert!(is_incomplete("print 1\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_is_incomplete_unterminated_string() {
+        assert!(is_incomplete("print \"abc\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_is_incomplete_extra_closing() {
+        // Let the compiler report the error instead of waiting for more input
+        assert!(!is_incomplete("print 1; }".as_bytes()));
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        let mut scanner = Scanner::new("a\r\nb\r\n\r\nc".as_bytes());
+        assert_eq!(1, scanner.scan_token().line);
+        assert_eq!(2, scanner.scan_token().line);
+        assert_eq!(4, scanner.scan_token().line);
+    }
+
+    #[test]
+    fn test_cr_line_endings() {
+        let mut scanner = Scanner::new("a\rb\r\rc".as_bytes());
+        assert_eq!(1, scanner.scan_token().line);
+        assert_eq!(2, scanner.scan_token().line);
+        assert_eq!(4, scanner.scan_token().line);
+    }
+
+    #[test]
+    fn test_crlf_comment_at_end() {
+        let mut scanner = Scanner::new("a // comment\r\n".as_bytes());
+        assert_eq!(1, scanner.scan_token().line);
+        let token = scanner.scan_token();
+        assert_eq!(TokenType::Eof, token.t_type);
+        assert_eq!(2, token.line);
+    }
+
+    #[test]
+    fn test_column_after_crlf() {
+        let mut scanner = Scanner::new("a\r\n  b\r  c".as_bytes());
+        assert_eq!((1, 1), position(scanner.scan_token()));
+        assert_eq!((2, 3), position(scanner.scan_token()));
+        assert_eq!((3, 3), position(scanner.scan_token()));
+    }
+
+    #[test]
+    fn test_cr_ends_comment() {
+        let mut scanner = Scanner::new("// comment\rvar".as_bytes());
+        let token = scanner.scan_token();
+        assert_eq!(TokenType::Var, token.t_type);
+        assert_eq!(2, token.line);
+    }
+
+    #[test]
+    fn test_string_escaped_quote() {
+        let mut scanner = Scanner::new(r#""a\"b" c"#.as_bytes());
+        let token = scanner.scan_token();
+        assert_eq!(TokenType::Strings, token.t_type);
+        assert_eq!(6, token.length);
+        assert_eq!(TokenType::Identifier, scanner.scan_token().t_type);
+    }
+
+    #[test]
+    fn test_column() {
+        let mut scanner = Scanner::new("var a;\n  a = 10;".as_bytes());
+        assert_eq!((1, 1), position(scanner.scan_token()));
+        assert_eq!((1, 5), position(scanner.scan_token()));
+        assert_eq!((1, 6), position(scanner.scan_token()));
+        assert_eq!((2, 3), position(scanner.scan_token()));
+        assert_eq!((2, 5), position(scanner.scan_token()));
+        assert_eq!((2, 7), position(scanner.scan_token()));
+        assert_eq!((2, 9), position(scanner.scan_token()));
+    }
+
+    #[test]
+    fn test_column_after_multiline_string() {
+        let mut scanner = Scanner::new("\"a\nb\" c".as_bytes());
+        assert_eq!((1, 1), position(scanner.scan_token()));
+        assert_eq!((2, 4), position(scanner.scan_token()));
+    }
+
+    fn position(token: Token) -> (usize, usize) {
+        (token.line, token.column)
+    }
+
+    #[test]
+    fn test_string_line_endings() {
+        let mut scanner = Scanner::new("\"a\r\nb\rc\" d".as_bytes());
+        assert_eq!(TokenType::Strings, scanner.scan_token().t_type);
+        assert_eq!(3, scanner.scan_token().line);
+    }
+
+    #[test]
+    fn test_raw_string_line_endings() {
+        let mut scanner = Scanner::new("r\"a\r\nb\rc\" d".as_bytes());
+        assert_eq!(TokenType::RawStrings, scanner.scan_token().t_type);
+        assert_eq!(3, scanner.scan_token().line);
+    }
 }
@@ -1,10 +1,16 @@
 use crate::value::Value;
 
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct Stack {
     pub values: Vec<Value>,
+    // pushing past `max` values fails
+    max: usize,
 }
 
+// Returned by `push` when the stack is full
+#[derive(Debug, PartialEq, Eq)]
+pub struct StackOverflow;
+
 impl Iterator for Stack {
     type Item = Value;
 
@@ -15,17 +21,36 @@ impl Iterator for Stack {
 
 impl Stack {
     pub fn new() -> Stack {
-        Stack { values: Vec::new() }
+        Stack {
+            values: Vec::new(),
+            max: usize::MAX,
+        }
     }
 
     pub fn with_capacity(capacity: usize) -> Stack {
         Stack {
             values: Vec::with_capacity(capacity),
+            max: usize::MAX,
+        }
+    }
+
+    pub fn with_max(max: usize) -> Stack {
+        Stack {
+            values: Vec::new(),
+            max,
         }
     }
 
-    pub fn push(&mut self, value: Value) {
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+    }
+
+    pub fn push(&mut self, value: Value) -> Result<(), StackOverflow> {
+        if self.values.len() >= self.max {
+            return Err(StackOverflow);
+        }
         self.values.push(value);
+        Ok(())
     }
 
     pub fn pop(&mut self) -> Option<Value> {
@@ -53,6 +78,12 @@ impl Stack {
     }
 }
 
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,18 +91,27 @@ mod tests {
     #[test]
     fn test_push() {
         let mut stack = Stack::new();
-        stack.push(Value::Number(1.0));
-        stack.push(Value::Number(2.0));
-        stack.push(Value::Number(3.0));
+        stack.push(Value::Number(1.0)).unwrap();
+        stack.push(Value::Number(2.0)).unwrap();
+        stack.push(Value::Number(3.0)).unwrap();
         assert_eq!(stack.len(), 3);
     }
 
+    #[test]
+    fn test_push_past_max() {
+        let mut stack = Stack::with_max(2);
+        stack.push(Value::Number(1.0)).unwrap();
+        stack.push(Value::Number(2.0)).unwrap();
+        assert_eq!(stack.push(Value::Number(3.0)), Err(StackOverflow));
+        assert_eq!(stack.len(), 2);
+    }
+
     #[test]
     fn test_pop() {
         let mut stack = Stack::new();
-        stack.push(Value::Number(1.0));
-        stack.push(Value::Number(2.0));
-        stack.push(Value::Number(3.0));
+        stack.push(Value::Number(1.0)).unwrap();
+        stack.push(Value::Number(2.0)).unwrap();
+        stack.push(Value::Number(3.0)).unwrap();
         assert_eq!(stack.pop(), Some(Value::Number(3.0)));
         assert_eq!(stack.pop(), Some(Value::Number(2.0)));
         assert_eq!(stack.pop(), Some(Value::Number(1.0)));
@@ -81,9 +121,9 @@ mod tests {
     #[test]
     fn test_peek() {
         let mut stack = Stack::new();
-        stack.push(Value::Number(1.0));
-        stack.push(Value::Number(2.0));
-        stack.push(Value::Number(3.0));
+        stack.push(Value::Number(1.0)).unwrap();
+        stack.push(Value::Number(2.0)).unwrap();
+        stack.push(Value::Number(3.0)).unwrap();
         assert_eq!(stack.peek(0), Some(&Value::Number(3.0)));
         assert_eq!(stack.peek(1), Some(&Value::Number(2.0)));
         assert_eq!(stack.peek(2), Some(&Value::Number(1.0)));
@@ -93,18 +133,18 @@ mod tests {
     #[test]
     fn test_len() {
         let mut stack = Stack::new();
-        stack.push(Value::Number(1.0));
-        stack.push(Value::Number(2.0));
-        stack.push(Value::Number(3.0));
+        stack.push(Value::Number(1.0)).unwrap();
+        stack.push(Value::Number(2.0)).unwrap();
+        stack.push(Value::Number(3.0)).unwrap();
         assert_eq!(stack.len(), 3);
     }
 
     #[test]
     fn test_reset() {
         let mut stack = Stack::new();
-        stack.push(Value::Number(1.0));
-        stack.push(Value::Number(2.0));
-        stack.push(Value::Number(3.0));
+        stack.push(Value::Number(1.0)).unwrap();
+        stack.push(Value::Number(2.0)).unwrap();
+        stack.push(Value::Number(3.0)).unwrap();
         stack.reset();
         assert_eq!(stack.len(), 0);
     }
@@ -113,7 +153,7 @@ mod tests {
     fn test_is_empty() {
         let mut stack = Stack::new();
         assert!(stack.is_empty());
-        stack.push(Value::Number(1.0));
+        stack.push(Value::Number(1.0)).unwrap();
         assert!(!stack.is_empty());
         stack.pop();
         assert!(stack.is_empty());
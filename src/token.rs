@@ -5,6 +5,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -12,25 +14,33 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
 
     // One or two character tokens.
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    EqualEqualEqual,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
+    StarStar,
+    QuestionQuestion,
 
     // Literals
     Identifier,
     Strings,
+    // r"..." where backslashes are kept as written
+    RawStrings,
     Number,
 
     // Keywords
     And,
+    Break,
     Class,
+    Const,
     Else,
     False,
     Fun,
@@ -45,15 +55,102 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Xor,
 
     Error,
     Eof,
 }
 
+impl TokenType {
+    pub const COUNT: usize = TokenType::Eof as usize + 1;
+
+    // Every token type in declaration order
+    pub const ALL: [TokenType; TokenType::COUNT] = [
+        TokenType::LeftParen,
+        TokenType::RightParen,
+        TokenType::LeftBrace,
+        TokenType::RightBrace,
+        TokenType::LeftBracket,
+        TokenType::RightBracket,
+        TokenType::Comma,
+        TokenType::Dot,
+        TokenType::Minus,
+        TokenType::Plus,
+        TokenType::Semicolon,
+        TokenType::Slash,
+        TokenType::Star,
+        TokenType::Percent,
+        TokenType::Bang,
+        TokenType::BangEqual,
+        TokenType::Equal,
+        TokenType::EqualEqual,
+        TokenType::EqualEqualEqual,
+        TokenType::Greater,
+        TokenType::GreaterEqual,
+        TokenType::Less,
+        TokenType::LessEqual,
+        TokenType::StarStar,
+        TokenType::QuestionQuestion,
+        TokenType::Identifier,
+        TokenType::Strings,
+        TokenType::RawStrings,
+        TokenType::Number,
+        TokenType::And,
+        TokenType::Break,
+        TokenType::Class,
+        TokenType::Const,
+        TokenType::Else,
+        TokenType::False,
+        TokenType::Fun,
+        TokenType::For,
+        TokenType::If,
+        TokenType::Nil,
+        TokenType::Or,
+        TokenType::Print,
+        TokenType::Return,
+        TokenType::Super,
+        TokenType::This,
+        TokenType::True,
+        TokenType::Var,
+        TokenType::While,
+        TokenType::Xor,
+        TokenType::Error,
+        TokenType::Eof,
+    ];
+}
+
 #[derive(Copy, Clone)]
 pub struct Token {
     pub t_type: TokenType,
     pub start: usize,
     pub length: usize,
     pub line: usize,
+    // 1-based byte column of the first character of the token
+    pub column: usize,
+    // where the token ends, the column is the one right after its last byte
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Token {
+    // The bytes of the token in the source it was scanned from
+    pub fn lexeme<'a>(&self, source: &'a [u8]) -> &'a [u8] {
+        &source[self.start..self.start + self.length]
+    }
+
+    // The byte range of the token, the end is exclusive
+    pub fn span(&self) -> (usize, usize) {
+        (self.start, self.start + self.length)
+    }
+
+    // (line, column) of the first byte of the token
+    pub fn start_position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    // (line, column) right after the last byte of the token, a multi-line string ends on a later
+    // line than it starts
+    pub fn end_position(&self) -> (usize, usize) {
+        (self.end_line, self.end_column)
+    }
 }
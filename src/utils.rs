@@ -4,15 +4,97 @@ pub fn convert_slice_to_string(source: &[u8], start: usize, end: usize) -> Strin
     String::from_utf8(source[start..end].to_vec()).expect("cannot get string value")
 }
 
-pub fn is_falsey(value: &Value) -> bool {
-    match value {
-        Value::Nil => true,
-        Value::Bool(b) => !b,
-        _ => false,
+// Decode the escapes of a string literal body: \n \t \r \0 \\ \" plus \xHH for a single byte and
+// \u{H..H} for a unicode code point. The decoded bytes must be valid UTF-8.
+pub fn unescape(source: &[u8]) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(source.len());
+    let mut idx = 0;
+    while idx < source.len() {
+        if source[idx] != b'\\' {
+            bytes.push(source[idx]);
+            idx += 1;
+            continue;
+        }
+
+        let escape = source.get(idx + 1).copied().unwrap_or(b'\0');
+        idx += 2;
+        match escape {
+            b'n' => bytes.push(b'\n'),
+            b't' => bytes.push(b'\t'),
+            b'r' => bytes.push(b'\r'),
+            b'0' => bytes.push(b'\0'),
+            b'\\' => bytes.push(b'\\'),
+            b'"' => bytes.push(b'"'),
+            b'x' => {
+                let digits = source
+                    .get(idx..idx + 2)
+                    .ok_or("Expect two hex digits after '\\x'.")?;
+                let byte = parse_hex(digits).ok_or("Expect two hex digits after '\\x'.")?;
+                bytes.push(byte as u8);
+                idx += 2;
+            }
+            b'u' => {
+                if source.get(idx) != Some(&b'{') {
+                    return Err("Expect '{...}' after '\\u'.".to_string());
+                }
+                let end = source[idx..]
+                    .iter()
+                    .position(|c| *c == b'}')
+                    .map(|position| idx + position)
+                    .ok_or("Expect '{...}' after '\\u'.")?;
+                let digits = &source[idx + 1..end];
+                if digits.is_empty() || digits.len() > 6 {
+                    return Err("Expect 1 to 6 hex digits in '\\u{...}'.".to_string());
+                }
+                let code = parse_hex(digits).ok_or("Invalid hex digit in '\\u{...}'.")?;
+                let c = char::from_u32(code).ok_or("Invalid unicode code point in '\\u{...}'.")?;
+                let mut buffer = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                idx = end + 1;
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid escape sequence '\\{}'.",
+                    char::from(escape).escape_default()
+                ))
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| "String escapes must form valid UTF-8.".to_string())
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    let digits = std::str::from_utf8(digits).ok()?;
+    if !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+// `==` semantics, numbers within `epsilon` of each other are equal when it is set
+pub fn values_equal(a: &Value, b: &Value, epsilon: Option<f64>) -> bool {
+    match (a, b, epsilon) {
+        (Value::Number(x), Value::Number(y), Some(epsilon)) => (x - y).abs() <= epsilon,
+        _ => a == b,
     }
 }
 
+#[cfg(debug_assertions)]
+thread_local! {
+    static HASH_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// How often `hash` ran on this thread, debug builds only. Lets tests check that a hot path does
+// not rehash.
+#[cfg(debug_assertions)]
+pub fn hash_calls() -> usize {
+    HASH_CALLS.with(|calls| calls.get())
+}
+
 pub fn hash(key: &str) -> u64 {
+    #[cfg(debug_assertions)]
+    HASH_CALLS.with(|calls| calls.set(calls.get() + 1));
+
     let mut hash = 0xcbf29ce484222325;
 
     for c in key.as_bytes() {
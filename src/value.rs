@@ -3,9 +3,12 @@ use std::fmt::Display;
 use crate::objects::{ObjClosure, ObjFunction, ObjNative};
 
 use gc_derive::{Finalize, Trace};
-use rox_gc::Gc;
+use rox_gc::{Gc, GcCell};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Trace, Finalize)]
+// Lists are mutable and shared, every copy of the value points to the same elements
+pub type ObjList = GcCell<Vec<Value>>;
+
+#[derive(Debug, Clone, PartialOrd, Trace, Finalize)]
 pub enum Value {
     Deault,
     Bool(bool),
@@ -15,6 +18,84 @@ pub enum Value {
     Function(Gc<ObjFunction>),
     NativeFunction(Gc<ObjNative>),
     Closure(Gc<ObjClosure>),
+    List(Gc<ObjList>),
+}
+
+impl Value {
+    // Only nil and false are falsey, `0` and `""` are truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !self.is_falsey()
+    }
+
+    // Identity comparison, heap allocated values are identical only when they point to the same
+    // `Gc` box while `==` compares their content
+    pub fn is_identical(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => Gc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Gc::ptr_eq(a, b),
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => Gc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Gc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Gc::ptr_eq(a, b),
+            _ => self == other,
+        }
+    }
+
+    // The `Gc` box behind a heap allocated value, `None` for the others
+    pub fn address(&self) -> Option<*const ()> {
+        match self {
+            Value::String(v) => Some(Gc::as_ptr(v) as *const ()),
+            Value::Function(v) => Some(Gc::as_ptr(v) as *const ()),
+            Value::NativeFunction(v) => Some(Gc::as_ptr(v) as *const ()),
+            Value::Closure(v) => Some(Gc::as_ptr(v) as *const ()),
+            Value::List(v) => Some(Gc::as_ptr(v) as *const ()),
+            Value::Deault | Value::Bool(_) | Value::Nil | Value::Number(_) => None,
+        }
+    }
+
+    // For debugging, e.g. "function@0x5581c7a3e2d0", two values printing the same address share
+    // their state. Values which are not heap allocated print as usual.
+    pub fn debug_address(&self) -> String {
+        match self.address() {
+            Some(address) => format!("{}@{:p}", self.type_name(), address),
+            None => self.to_string(),
+        }
+    }
+
+    // What `type_of` returns, also used in runtime errors e.g. "got number"
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Deault => "default",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::NativeFunction(_) | Value::Closure(_) => "function",
+            Value::List(_) => "list",
+        }
+    }
+}
+
+// Numbers, strings and lists compare by content. Functions, closures and natives compare by
+// identity, two distinct closures are never equal even when they wrap the same function.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Deault, Value::Deault) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::List(a), Value::List(b)) => lists_equal(a, b, &mut Vec::new()),
+            (Value::Function(a), Value::Function(b)) => Gc::ptr_eq(a, b),
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => Gc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Gc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 impl Display for Value {
@@ -22,12 +103,162 @@ impl Display for Value {
         match self {
             Value::Deault => write!(f, "Default"),
             Value::Bool(b) => write!(f, "{}", b),
-            Value::Nil => write!(f, "Nil"),
+            Value::Nil => write!(f, "nil"),
             Value::Number(n) => write!(f, "{}", n),
             Value::String(s) => write!(f, "{}", s),
             Value::NativeFunction(_) => write!(f, "Native Function"),
-            Value::Function(_) => write!(f, "Function"),
-            Value::Closure(_) => write!(f, "Closure"),
+            Value::Function(function) => write!(f, "{}", **function),
+            Value::Closure(closure) => write!(f, "{}", closure.function),
+            Value::List(list) => write_list(list, f, &mut Vec::new()),
+        }
+    }
+}
+
+// `comparing` holds the pairs of lists being compared further up, a cyclic list would otherwise
+// compare its content forever. A pair seen again is treated as equal, any difference shows up
+// elsewhere in the content.
+fn lists_equal(
+    a: &Gc<ObjList>,
+    b: &Gc<ObjList>,
+    comparing: &mut Vec<(*const ObjList, *const ObjList)>,
+) -> bool {
+    let pair = (Gc::as_ptr(a), Gc::as_ptr(b));
+    if Gc::ptr_eq(a, b) || comparing.contains(&pair) {
+        return true;
+    }
+
+    let (a, b) = (a.borrow(), b.borrow());
+    if a.len() != b.len() {
+        return false;
+    }
+    comparing.push(pair);
+    let equal = a.iter().zip(b.iter()).all(|pair| match pair {
+        (Value::List(x), Value::List(y)) => lists_equal(x, y, comparing),
+        (x, y) => x == y,
+    });
+    comparing.pop();
+    equal
+}
+
+// `visiting` holds the lists being printed further up, a list inside itself prints as `[...]`
+fn write_list(
+    list: &Gc<ObjList>,
+    f: &mut std::fmt::Formatter<'_>,
+    visiting: &mut Vec<*const ObjList>,
+) -> std::fmt::Result {
+    let address = Gc::as_ptr(list);
+    if visiting.contains(&address) {
+        return write!(f, "[...]");
+    }
+
+    visiting.push(address);
+    write!(f, "[")?;
+    for (i, value) in list.borrow().iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match value {
+            Value::List(inner) => write_list(inner, f, visiting)?,
+            _ => write!(f, "{}", value)?,
         }
     }
+    visiting.pop();
+    write!(f, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_eq_by_content() {
+        let a = Value::String(Gc::new(String::from("rox")));
+        let b = Value::String(Gc::new(String::from("rox")));
+        assert_eq!(a, b);
+        assert!(!a.is_identical(&b));
+    }
+
+    #[test]
+    fn test_debug_address() {
+        let closure = Value::Closure(Gc::new(ObjClosure::new(ObjFunction::new(String::from(
+            "f",
+        )))));
+        let other = Value::Closure(Gc::new(ObjClosure::new(ObjFunction::new(String::from(
+            "f",
+        )))));
+        assert_eq!(closure.debug_address(), closure.clone().debug_address());
+        assert_ne!(closure.debug_address(), other.debug_address());
+        assert!(closure.debug_address().starts_with("function@0x"));
+        assert_eq!("1", Value::Number(1.0).debug_address());
+        assert_eq!(None, Value::Nil.address());
+    }
+
+    #[test]
+    fn test_truthiness() {
+        assert!(Value::Nil.is_falsey());
+        assert!(Value::Bool(false).is_falsey());
+
+        let native = ObjNative::new(String::from("f"), |_| Ok(Value::Nil));
+        let truthy = [
+            Value::Deault,
+            Value::Bool(true),
+            Value::Number(0.0),
+            Value::Number(f64::NAN),
+            Value::String(Gc::new(String::new())),
+            Value::Function(Gc::new(ObjFunction::new(String::from("f")))),
+            Value::NativeFunction(Gc::new(native)),
+            Value::Closure(Gc::new(ObjClosure::new(ObjFunction::new(String::from(
+                "f",
+            ))))),
+            Value::List(Gc::new(GcCell::new(Vec::new()))),
+        ];
+        for value in truthy {
+            assert!(value.is_truthy(), "{:?}", value);
+            assert!(!value.is_falsey(), "{:?}", value);
+        }
+        assert!(!Value::Nil.is_truthy());
+        assert!(!Value::Bool(false).is_truthy());
+    }
+
+    #[test]
+    fn test_self_containing_list() {
+        let list = Gc::new(GcCell::new(vec![Value::Number(1.0)]));
+        let inner = Value::List(Gc::new(GcCell::new(vec![Value::List(list.clone())])));
+        list.borrow_mut().push(inner);
+        let value = Value::List(list);
+        assert_eq!("[1, [[...]]]", value.to_string());
+        assert_eq!(value, value.clone());
+
+        let other = Gc::new(GcCell::new(vec![Value::Number(1.0)]));
+        let inner = Value::List(Gc::new(GcCell::new(vec![Value::List(other.clone())])));
+        other.borrow_mut().push(inner);
+        assert_eq!(value, Value::List(other.clone()));
+        other.borrow_mut().push(Value::Nil);
+        assert_ne!(value, Value::List(other));
+    }
+
+    #[test]
+    fn test_type_name() {
+        assert_eq!("number", Value::Number(1.0).type_name());
+        assert_eq!("nil", Value::Nil.type_name());
+        assert_eq!("string", Value::String(Gc::new(String::new())).type_name());
+    }
+
+    #[test]
+    fn test_function_eq_by_identity() {
+        let f = Gc::new(ObjFunction::new(String::from("f")));
+        let same = Value::Function(f.clone());
+        assert_eq!(Value::Function(f), same);
+        let copy = Value::Function(Gc::new(ObjFunction::new(String::from("f"))));
+        assert_ne!(same, copy);
+
+        let closure = Value::Closure(Gc::new(ObjClosure::new(ObjFunction::new(String::from(
+            "f",
+        )))));
+        let other = Value::Closure(Gc::new(ObjClosure::new(ObjFunction::new(String::from(
+            "f",
+        )))));
+        assert_eq!(closure, closure.clone());
+        assert_ne!(closure, other);
+    }
 }
@@ -1,28 +1,45 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use rox_gc::Gc;
+use rox_gc::{Gc, GcCell, GcConfig};
 
 use crate::chunk::Chunk;
 use crate::compiler::Parser;
-use crate::objects::{ObjClosure, ObjUpValue, MAX_UPVALUES};
+use crate::objects::{ObjClosure, ObjFunction, ObjUpValue};
 use crate::{
-    hashtable::HashTable,
-    objects::{HashKeyString, ObjNative},
+    hashtable::{HashTable, TableEntry},
+    limits::{FRAME_MAX, MAX_UPVALUES, STACK_MAX},
+    objects::{NativeFn, ObjNative},
     op_code::OpCode,
     stack::Stack,
-    utils::{hash, is_falsey},
-    value::Value,
+    utils::values_equal,
+    value::{ObjList, Value},
 };
 
-const FRAME_MAX: usize = 64;
-
+// The errors have already been reported on stderr, the messages are kept for embedders
 #[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    // Every compile error, one per line, e.g. "[line 1:7] Error at ';': Expect expression."
+    CompileError(String),
+    // The runtime error and its line, e.g. "undefined variable 'a' [line 1]"
+    RuntimeError(String),
     Default,
 }
 
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CompileError(message) => write!(f, "{}", message),
+            Self::RuntimeError(message) => write!(f, "Runtime error: {}", message),
+            Self::Default => write!(f, "Interpret error"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 // represents a single ongoing function call
 // TODO - function calls are a core operation, can we do not use heap allocation here?
@@ -40,52 +57,204 @@ impl CallFrame {
             slots: 0,
         }
     }
+
+    // The line of the instruction the frame executes, a frame which didn't start yet reports the
+    // line of its first instruction
+    fn line(&self) -> usize {
+        let lines = &self.closure.function.chunk.lines;
+        lines
+            .get(self.ip.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
 }
 
 pub struct Vm {
     stack: Stack,
-    table: HashTable,
+    table: HashTable<Value>,
     frames: Vec<CallFrame>,
     // Gc managed heap allocation is used for both vm open_values
     // and ObjClosure upvalues
     open_values: Vec<Gc<ObjUpValue>>,
+    // When enabled, every instruction and the current stack are written to `trace_output`
+    // before the instruction executes
+    trace: bool,
+    trace_output: Box<dyn Write>,
+    // Tolerance for numeric `==`, `None` compares numbers exactly
+    float_epsilon: Option<f64>,
+    // Compile with the peephole optimizer, off by default
+    optimize: bool,
+    // While `interpret_capture` runs the printed lines are collected here instead of stdout
+    captured: Option<String>,
+    // Globals declared with `const` by any script so far, later scripts can't assign them
+    const_globals: HashSet<String>,
 }
 
 impl Vm {
+    // The collector keeps whatever config the thread already has, see `with_gc_threshold`
     pub fn new() -> Self {
         let mut res = Self {
-            stack: Stack::new(),
+            stack: Stack::with_max(STACK_MAX),
             table: HashTable::new(),
             frames: Vec::with_capacity(FRAME_MAX),
             open_values: Vec::with_capacity(MAX_UPVALUES),
+            trace: false,
+            trace_output: Box::new(io::stderr()),
+            float_epsilon: None,
+            optimize: false,
+            captured: None,
+            const_globals: HashSet::new(),
         };
         res.define_native(ObjNative::new("clock".to_string(), clock_native));
+        res.define_native(ObjNative::new("now_millis".to_string(), now_millis_native));
+        res.define_native(ObjNative::new("sleep".to_string(), sleep_native));
+        res.define_native(ObjNative::new("push".to_string(), push_native));
+        res.define_native(ObjNative::new("pop".to_string(), pop_native));
+        res.define_native(ObjNative::new("len".to_string(), len_native));
+        res.define_native(ObjNative::new("substr".to_string(), substr_native));
+        res.define_native(ObjNative::new("type_of".to_string(), type_of_native));
+        res.define_native(ObjNative::new("assert".to_string(), assert_native));
+        res.define_native(ObjNative::new("assert_eq".to_string(), assert_eq_native));
 
         res
     }
 
+    // The collector is per thread, so the threshold applies to every vm of the calling thread.
+    // A higher threshold means fewer but longer collections.
+    pub fn with_gc_threshold(threshold: usize) -> Self {
+        rox_gc::configure(GcConfig {
+            threshold,
+            ..GcConfig::default()
+        });
+        Self::new()
+    }
+
     pub fn initialize(&mut self) {
+        self.reset();
+    }
+
+    // Drop everything left behind by a failed run so the vm can interpret the next input,
+    // globals are kept so a REPL session does not lose its definitions
+    pub fn reset(&mut self) {
         self.stack.reset();
+        self.frames.clear();
+        self.open_values.clear();
     }
 
+    // Toggle the execution trace, it is off by default so it does not pollute the program output
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    // The deepest the value stack may grow, `STACK_MAX` by default
+    pub fn set_stack_max(&mut self, max: usize) {
+        self.stack.set_max(max);
+    }
+
+    pub fn set_optimize(&mut self, enabled: bool) {
+        self.optimize = enabled;
+    }
+
+    // e.g. with `Some(1e-9)` the script `0.1 + 0.2 == 0.3` is true
+    pub fn set_float_epsilon(&mut self, epsilon: Option<f64>) {
+        self.float_epsilon = epsilon;
+    }
+
+    // Expose a host function to scripts as a global, e.g. `vm.register_native("answer", answer)`
+    // with `fn answer(_: &[Value]) -> Result<Value, String>`. An `Err` is reported as a runtime error.
+    pub fn register_native(&mut self, name: &str, function: NativeFn) {
+        self.define_native(ObjNative::new(name.to_string(), function));
+    }
+
+    // Every call compiles a fresh script function, but globals live in `self.table` which is never
+    // cleared, so definitions from previous calls (e.g. REPL lines) stay visible.
+    // The error carries the message which was reported, see `InterpretError`.
     pub fn interpret(&mut self, bytes: &str) -> Result<(), InterpretError> {
-        let parser = Parser::new(bytes.as_bytes());
-        match parser.compile() {
-            Ok(function) => {
-                // script function is always at the top of the stack
-                let closure = ObjClosure::new(function);
-                let gc_closure = Gc::new(closure);
-                self.pop();
-                self.push(Value::Closure(gc_closure.clone()));
-                self.call(&gc_closure, 0);
-                self.run()
+        self.interpret_bytes(bytes.as_bytes())
+    }
+
+    // Same as `interpret` for a script which may not be valid UTF-8, e.g. a file as read from
+    // disk. Invalid UTF-8 inside a string literal is a compile error, anywhere else it's an
+    // unexpected character.
+    pub fn interpret_bytes(&mut self, bytes: &[u8]) -> Result<(), InterpretError> {
+        let parser = self.parser(bytes);
+        match parser.compile_script(false) {
+            Ok(script) => {
+                self.const_globals.extend(script.const_globals);
+                self.run_script(script.function).map(|_| ())
             }
-            Err(_) => Err(InterpretError::CompileError),
+            Err(errors) => Err(InterpretError::CompileError(errors.join("\n"))),
         }
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    // Same as `interpret` but whatever the script prints is returned instead of written to stdout,
+    // e.g. `print 1 + 2;` gives "Printing value of 3\n". Errors are still reported on stderr.
+    pub fn interpret_capture(&mut self, bytes: &str) -> Result<String, InterpretError> {
+        self.captured = Some(String::new());
+        let result = self.interpret(bytes);
+        let output = self.captured.take().unwrap_or_default();
+        result.map(|_| output)
+    }
+
+    // Compile the script without running it, e.g. to dump its bytecode
+    pub fn compile(&self, bytes: &[u8]) -> Result<ObjFunction, InterpretError> {
+        self.parser(bytes)
+            .compile()
+            .map_err(|errors| InterpretError::CompileError(errors.join("\n")))
+    }
+
+    // Like `interpret`, but when the input ends with an expression statement its value is returned
+    // so the REPL can echo it, e.g. `1 + 1` gives `Some(Number(2))`
+    pub fn interpret_repl(&mut self, bytes: &str) -> Result<Option<Value>, InterpretError> {
+        let parser = self.parser(bytes.as_bytes());
+        match parser.compile_script(true) {
+            Ok(script) => {
+                self.const_globals.extend(script.const_globals);
+                let value = self.run_script(script.function)?;
+                Ok(script.echo.then_some(value))
+            }
+            Err(errors) => Err(InterpretError::CompileError(errors.join("\n"))),
+        }
+    }
+
+    // Globals are late bound, so a compiled script may use every global defined so far
+    fn parser<'a>(&self, bytes: &'a [u8]) -> Parser<'a> {
+        let mut parser = Parser::new(bytes);
+        parser.set_optimize(self.optimize);
+        parser.check_globals(self.table.keys().map(|key| key.value.clone()));
+        parser.const_globals(self.const_globals.iter().cloned());
+        parser
+    }
+
+    // `print` goes through here so `interpret_capture` can collect its output
+    fn print_line(&mut self, line: &str) {
+        match self.captured.as_mut() {
+            Some(captured) => {
+                captured.push_str(line);
+                captured.push('\n');
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    fn run_script(&mut self, function: ObjFunction) -> Result<Value, InterpretError> {
+        // script function is always at the top of the stack
+        let closure = ObjClosure::new(function);
+        let gc_closure = Gc::new(closure);
+        // a failed run leaves its frames and open upvalues behind, every script starts fresh
+        self.reset();
+        self.push(Value::Closure(gc_closure.clone()))?;
+        self.call(&gc_closure, 0)?;
+        self.run()
+    }
+
+    // Every value goes through here, a stack past its maximum depth is a runtime error instead of
+    // unbounded growth
+    fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        if self.stack.push(value).is_err() {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        Ok(())
     }
 
     fn pop(&mut self) -> Option<Value> {
@@ -97,36 +266,40 @@ impl Vm {
         self.stack.peek(distance)
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
         match &callee {
             // call a function will push the callee to call frame which represents a single ongoing function call
             Value::Closure(closure) => self.call(closure, arg_count),
             Value::NativeFunction(native) => {
                 let idx = self.stack.len() - arg_count;
-                let result = (native.func)(&self.stack.values[idx..]);
-                self.stack.values.truncate(idx - 1);
-                self.push(result);
-                true
+                match (native.func)(&self.stack.values[idx..]) {
+                    Ok(result) => {
+                        self.stack.values.truncate(idx - 1);
+                        self.push(result)
+                    }
+                    Err(msg) => Err(self.runtime_error(&msg)),
+                }
             }
             _ => {
-                println!("Can only call functions and classes.");
-                false
+                let message = format!(
+                    "Can only call functions and classes, got {}.",
+                    callee.type_name()
+                );
+                Err(self.runtime_error(&message))
             }
         }
     }
 
-    fn call(&mut self, closure: &ObjClosure, arg_count: usize) -> bool {
+    fn call(&mut self, closure: &ObjClosure, arg_count: usize) -> Result<(), InterpretError> {
         if arg_count != closure.function.arity as usize {
-            println!(
+            return Err(self.runtime_error(&format!(
                 "Expected {} arguments but got {}.",
                 closure.function.arity, arg_count
-            );
-            return false;
+            )));
         }
 
         if self.frames.len() == FRAME_MAX {
-            println!("Stack overflow!");
-            return false;
+            return Err(self.runtime_error("Stack overflow."));
         }
 
         // calculate the stack start slot for the function
@@ -135,13 +308,14 @@ impl Vm {
         frame.ip = 0;
         frame.slots = stack_top;
         self.frames.push(frame);
-        true
+        Ok(())
     }
 
     fn capture_upvalue(&mut self, index: usize) -> Gc<ObjUpValue> {
-        for vm_upvalue in self.open_values.iter_mut() {
+        // closures capturing the same local share one upvalue, so they see each other's writes
+        for vm_upvalue in self.open_values.iter() {
             if vm_upvalue.location == index {
-                return std::mem::take(vm_upvalue);
+                return vm_upvalue.clone();
             }
         }
         let upvalue = Gc::new(ObjUpValue::new(index));
@@ -168,22 +342,26 @@ impl Vm {
             .insert(native.name.clone(), Value::NativeFunction(Gc::new(native)));
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprint!("Runtime error: {}", message);
-
+    // Reports the error with a backtrace and returns it for the caller to propagate
+    fn runtime_error(&mut self, message: &str) -> InterpretError {
         let line = self.current_line();
+        let message = format!("{} [line {}]", message, line);
 
-        eprintln!(" [line {}]", line);
+        eprintln!("Runtime error: {}", message);
 
         for frame in self.frames.iter().rev() {
-            let function = &frame.closure.function;
-            let line = function.chunk.lines[frame.ip - 1];
-            eprintln!("[line {}] in {}", line, function.name.value);
+            eprintln!(
+                "[line {}] in {}",
+                frame.line(),
+                frame.closure.function.name.value
+            );
         }
 
         self.stack.reset();
+        InterpretError::RuntimeError(message)
     }
 
+    // Reports a runtime error when the operands do not fit the operation
     fn binary_operation(&mut self, code: OpCode) -> Result<(), InterpretError> {
         let (v1, v2) = (
             self.pop().expect("unable to pop value"),
@@ -194,72 +372,106 @@ impl Vm {
             OpCode::Add => {
                 if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
                     let result = x2 + x1;
-                    self.push(Value::Number(result));
+                    self.push(Value::Number(result))?;
                     Ok(())
                 } else if let (Value::String(s1), Value::String(s2)) = (&v1, &v2) {
                     let result = format!("{}{}", s2, s1);
-                    self.push(Value::String(Gc::new(result)));
+                    self.push(Value::String(Gc::new(result)))?;
                     Ok(())
                 } else {
-                    Err(InterpretError::RuntimeError)
+                    Err(self.runtime_error("operands must be two numbers or two strings"))
                 }
             }
             OpCode::Subtract => {
                 if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
                     let result = x2 - x1;
-                    self.push(Value::Number(result));
+                    self.push(Value::Number(result))?;
                     Ok(())
                 } else {
-                    self.push(v1);
-                    self.push(v2);
-                    Err(InterpretError::RuntimeError)
+                    Err(self.runtime_error("operands must be two numbers"))
                 }
             }
             OpCode::Multiply => {
                 if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
                     let result = x2 * x1;
-                    self.push(Value::Number(result));
+                    self.push(Value::Number(result))?;
                     Ok(())
                 } else {
-                    self.push(v1);
-                    self.push(v2);
-                    Err(InterpretError::RuntimeError)
+                    Err(self.runtime_error("operands must be two numbers"))
                 }
             }
+            // dividing by zero is an error rather than inf or NaN, for `/` and `%` alike
             OpCode::Divide => {
                 if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
+                    if *x1 == 0.0 {
+                        return Err(self.runtime_error("Division by zero."));
+                    }
                     let result = x2 / x1;
-                    self.push(Value::Number(result));
+                    self.push(Value::Number(result))?;
                     Ok(())
                 } else {
-                    self.push(v1);
-                    self.push(v2);
-                    Err(InterpretError::RuntimeError)
+                    Err(self.runtime_error("operands must be two numbers"))
+                }
+            }
+            OpCode::Modulo => {
+                if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
+                    if *x1 == 0.0 {
+                        return Err(self.runtime_error("Division by zero."));
+                    }
+                    let result = x2 % x1;
+                    self.push(Value::Number(result))?;
+                    Ok(())
+                } else {
+                    Err(self.runtime_error("operands must be two numbers"))
+                }
+            }
+            OpCode::Power => {
+                if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
+                    let result = x2.powf(*x1);
+                    self.push(Value::Number(result))?;
+                    Ok(())
+                } else {
+                    Err(self.runtime_error("operands must be two numbers"))
                 }
             }
             OpCode::Greater => {
                 if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
                     let result = x2 > x1;
-                    self.push(Value::Bool(result));
+                    self.push(Value::Bool(result))?;
                     Ok(())
                 } else {
-                    self.push(v1);
-                    self.push(v2);
-                    Err(InterpretError::RuntimeError)
+                    Err(self.runtime_error("operands must be two numbers"))
                 }
             }
             OpCode::Less => {
                 if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
                     let result = x2 < x1;
-                    self.push(Value::Bool(result));
+                    self.push(Value::Bool(result))?;
+                    Ok(())
+                } else {
+                    Err(self.runtime_error("operands must be two numbers"))
+                }
+            }
+            // `!(a < b)` and `!(a > b)`, so NaN compares the same as the unoptimized code
+            OpCode::GreaterEqual => {
+                if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
+                    let result = x2.partial_cmp(x1) != Some(Ordering::Less);
+                    self.push(Value::Bool(result))?;
+                    Ok(())
+                } else {
+                    Err(self.runtime_error("operands must be two numbers"))
+                }
+            }
+            OpCode::LessEqual => {
+                if let (Value::Number(x1), Value::Number(x2)) = (&v1, &v2) {
+                    let result = x2.partial_cmp(x1) != Some(Ordering::Greater);
+                    self.push(Value::Bool(result))?;
                     Ok(())
                 } else {
-                    self.push(v1);
-                    self.push(v2);
-                    Err(InterpretError::RuntimeError)
+                    Err(self.runtime_error("operands must be two numbers"))
                 }
             }
-            _ => Err(InterpretError::RuntimeError),
+            _ => unreachable!("{:?} is not a binary operation", code),
         }
     }
 
@@ -276,165 +488,175 @@ impl Vm {
     }
 
     fn current_line(&self) -> usize {
-        self.current_chunk().lines[self.current_frame().ip - 1]
+        self.current_frame().line()
     }
 
-    fn run(&mut self) -> Result<(), InterpretError> {
+    // Returns the value the script returned once the top-level frame is done
+    fn run(&mut self) -> Result<Value, InterpretError> {
         loop {
             let instruction = self.current_chunk().code[self.current_frame().ip];
-            // Enable this to see the chunk and stack
-            // self.current_chunk()
-            //     .disassemble_instruction(self.current_frame().ip);
-            // self.print_stack();
+            if self.trace {
+                self.trace_instruction(instruction);
+            }
             self.current_frame_mut().ip += 1;
+            // no catch-all arm, every opcode the compiler can emit must be handled here
             match instruction {
-                OpCode::Return => {
+                OpCode::Return | OpCode::ReturnNil => {
                     // When a function returns, we pop the top value off the stack and discard it.
-                    let res = self.pop().expect("unable to pop value");
+                    let res = if instruction == OpCode::ReturnNil {
+                        Value::Nil
+                    } else {
+                        self.pop().expect("unable to pop value")
+                    };
                     // Discard the call frame for the returning function.
                     let frame = self.frames.pop().expect("unable to pop frame");
                     self.close_upvalues(frame.slots);
                     if self.frames.is_empty() {
                         // we've finished executing the top-level code. We are done
-                        return Ok(());
+                        return Ok(res);
                     } else {
                         // the call is done, the caller does not need it anymore, the top of the stack
                         // ends up right at the beginning of the returning function's stack window
                         self.stack.values.truncate(frame.slots);
-                        self.push(res);
+                        self.push(res)?;
                     }
                 }
                 OpCode::Constant(v) => {
                     let val = self.current_chunk().constants[v].clone();
-                    self.push(val);
+                    self.push(val)?;
                 }
                 OpCode::Negative => match self.peek(0).expect("unable to peek value") {
                     Value::Number(_) => {
                         if let Value::Number(v) = self.pop().expect("unable to pop value") {
-                            self.push(Value::Number(-v));
+                            self.push(Value::Number(-v))?;
                         }
                     }
-                    _ => {
-                        println!("operand must be a number");
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    _ => return Err(self.runtime_error("operand must be a number")),
                 },
-                OpCode::Add => {
-                    if self.binary_operation(OpCode::Add).is_err() {
-                        self.runtime_error("operands must be two numbers or two strings");
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                OpCode::Subtract => {
-                    if self.binary_operation(OpCode::Subtract).is_err() {
-                        self.runtime_error("operands must be two numbers");
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                OpCode::Multiply => {
-                    if self.binary_operation(OpCode::Multiply).is_err() {
-                        self.runtime_error("operands must be two numbers");
-                        return Err(InterpretError::RuntimeError);
-                    }
-                }
-                OpCode::Divide => {
-                    if self.binary_operation(OpCode::Divide).is_err() {
-                        self.runtime_error("operands must be two numbers");
-                        return Err(InterpretError::RuntimeError);
+                // numbers are left on the stack as they are
+                OpCode::Positive => {
+                    if !matches!(self.peek(0), Some(Value::Number(_))) {
+                        return Err(self.runtime_error("operand must be a number"));
                     }
                 }
+                OpCode::Add => self.binary_operation(OpCode::Add)?,
+                OpCode::Subtract => self.binary_operation(OpCode::Subtract)?,
+                OpCode::Multiply => self.binary_operation(OpCode::Multiply)?,
+                OpCode::Divide => self.binary_operation(OpCode::Divide)?,
+                OpCode::Power => self.binary_operation(OpCode::Power)?,
+                OpCode::Modulo => self.binary_operation(OpCode::Modulo)?,
                 OpCode::Nil => {
-                    self.push(Value::Nil);
+                    self.push(Value::Nil)?;
                 }
                 OpCode::True => {
-                    self.push(Value::Bool(true));
+                    self.push(Value::Bool(true))?;
                 }
                 OpCode::False => {
-                    self.push(Value::Bool(false));
+                    self.push(Value::Bool(false))?;
                 }
                 OpCode::Not => {
                     let val = self.pop().expect("unable to pop value");
-                    self.push(Value::Bool(is_falsey(&val)));
+                    self.push(Value::Bool(val.is_falsey()))?;
                 }
                 OpCode::Equal => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::Bool(a == b));
+                    let b = self.pop().expect("unable to pop value");
+                    let a = self.pop().expect("unable to pop value");
+                    self.push(Value::Bool(values_equal(&a, &b, self.float_epsilon)))?;
+                }
+                OpCode::NotEqual => {
+                    let b = self.pop().expect("unable to pop value");
+                    let a = self.pop().expect("unable to pop value");
+                    self.push(Value::Bool(!values_equal(&a, &b, self.float_epsilon)))?;
+                }
+                OpCode::Xor => {
+                    let b = self.pop().expect("unable to pop value");
+                    let a = self.pop().expect("unable to pop value");
+                    self.push(Value::Bool(a.is_truthy() != b.is_truthy()))?;
+                }
+                OpCode::Identical => {
+                    let b = self.pop().expect("unable to pop value");
+                    let a = self.pop().expect("unable to pop value");
+                    self.push(Value::Bool(a.is_identical(&b)))?;
                 }
                 OpCode::Greater => self.binary_operation(OpCode::Greater)?,
                 OpCode::Less => self.binary_operation(OpCode::Less)?,
+                OpCode::GreaterEqual => self.binary_operation(OpCode::GreaterEqual)?,
+                OpCode::LessEqual => self.binary_operation(OpCode::LessEqual)?,
                 OpCode::Pop => {
                     self.pop();
                 }
+                OpCode::PopN(count) => {
+                    let len = self.stack.len();
+                    self.stack.values.truncate(len - count);
+                }
                 OpCode::CloseUpvalue => {
                     self.close_upvalues(self.stack.values.len() - 1);
                     self.pop();
                 }
                 OpCode::Print => {
                     let val = self.pop().expect("unable to pop value");
-                    match &val {
-                        Value::Function(v) => println!("{}", v.name.value),
-                        Value::String(v) => println!("Printing value of {}", v),
-                        Value::Number(v) => println!("Printing value of {}", v),
-                        Value::Bool(v) => println!("Printing value of {}", v),
-                        Value::Nil => println!("nil"),
-                        _ => println!("unknown value"),
-                    }
+                    let line = match &val {
+                        Value::String(_) | Value::Number(_) | Value::Bool(_) | Value::List(_) => {
+                            format!("Printing value of {}", val)
+                        }
+                        _ => print_text(&val),
+                    };
+                    self.print_line(&line);
+                }
+                OpCode::PrintMulti(count) => {
+                    let len = self.stack.len();
+                    let values: Vec<String> = self
+                        .stack
+                        .values
+                        .drain(len - count..)
+                        .map(|v| print_text(&v))
+                        .collect();
+                    self.print_line(&format!("Printing value of {}", values.join(" ")));
                 }
+                // the keys of global names are hashed once by the compiler, see `Chunk::keys`
                 OpCode::DefineGlobal(v) => {
-                    if let Value::String(s) =
-                        &self.current_frame().closure.function.chunk.constants[v]
-                    {
-                        let key = HashKeyString {
-                            hash: hash(s),
-                            value: s.to_string(),
-                        };
-                        let val = self.pop().expect("unable to pop value");
-                        self.table.insert(key, val);
+                    let val = self.stack.pop().expect("unable to pop value");
+                    // borrowed like in SetGlobal, the key is only cloned when the global is new
+                    let frame = self.frames.last().expect("unable to get current frame");
+                    let key = frame.closure.function.chunk.global_key(v);
+                    match self.table.entry(key) {
+                        TableEntry::Occupied(slot) => *slot = val,
+                        TableEntry::Vacant(entry) => {
+                            entry.insert(val);
+                        }
                     }
                 }
                 OpCode::GetGlobal(v) => {
-                    if let Value::String(s) =
-                        &self.current_frame().closure.function.chunk.constants[v]
-                    {
-                        let key = HashKeyString {
-                            hash: hash(s),
-                            value: s.to_string(),
-                        };
-                        if let Some(val) = self.table.get(&key) {
-                            self.push(val.clone());
-                        } else {
-                            self.runtime_error(format!("undefined variable '{}'", s).as_str());
-                            return Err(InterpretError::RuntimeError);
-                        }
+                    let key = self.current_chunk().global_key(v);
+                    if let Some(val) = self.table.get(key) {
+                        let val = val.clone();
+                        self.push(val)?;
+                    } else {
+                        let message = format!("undefined variable '{}'", key.value);
+                        return Err(self.runtime_error(&message));
                     }
                 }
                 OpCode::SetGlobal(v) => {
-                    if let Value::String(s) =
-                        &self.current_frame().closure.function.chunk.constants[v]
-                    {
-                        let key = HashKeyString {
-                            hash: hash(s),
-                            value: s.to_string(),
-                        };
-                        if self.table.get(&key).is_some() {
-                            // We do not want to pop the value off the stack because it might be
-                            // re-used in other places. e.g. a = 1; b = a + 1; c = 2+a; print c;
-                            // should print 3
-                            let val = self.peek(0).expect("unable to peek value");
-                            // insert would replace the value with the same key
-                            self.table.insert(key, val.clone());
-                        } else {
-                            // when the key does note exist in the global has table, we throw a runtime error
-                            self.runtime_error(format!("undefined variable '{}'", s).as_str());
-                            return Err(InterpretError::RuntimeError);
-                        }
+                    // the frames, the stack and the table are borrowed separately so the key
+                    // does not have to be cloned
+                    let frame = self.frames.last().expect("unable to get current frame");
+                    let key = frame.closure.function.chunk.global_key(v);
+                    // We do not want to pop the value off the stack because it might be
+                    // re-used in other places. e.g. a = 1; b = a + 1; c = 2+a; print c;
+                    // should print 3
+                    let val = self.stack.peek(0).expect("unable to peek value").clone();
+                    if let TableEntry::Occupied(slot) = self.table.entry(key) {
+                        *slot = val;
+                    } else {
+                        // when the key does note exist in the global has table, we throw a runtime error
+                        let message = format!("undefined variable '{}'", key.value);
+                        return Err(self.runtime_error(&message));
                     }
                 }
                 OpCode::GetLocal(index) => {
                     let addr = self.current_frame().slots + index + 1;
                     let val = &self.stack.values[addr];
-                    self.push(val.clone());
+                    self.push(val.clone())?;
                 }
                 OpCode::GetUpvalue(index) => {
                     let val = &self.current_frame().closure.obj_upvalues[index];
@@ -447,7 +669,7 @@ impl Vm {
                         }
                     };
 
-                    self.push(res);
+                    self.push(res)?;
                 }
                 OpCode::SetLocal(index) => {
                     let addr = self.current_frame().slots + index + 1;
@@ -465,7 +687,17 @@ impl Vm {
                     }
                 }
                 OpCode::JumpIfFalse(offset) => {
-                    if is_falsey(self.peek(0).expect("unable to peek value")) {
+                    if self.peek(0).expect("unable to peek value").is_falsey() {
+                        self.current_frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::JumpIfTrue(offset) => {
+                    if self.peek(0).expect("unable to peek value").is_truthy() {
+                        self.current_frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::JumpIfNotNil(offset) => {
+                    if *self.peek(0).expect("unable to peek value") != Value::Nil {
                         self.current_frame_mut().ip += offset as usize;
                     }
                 }
@@ -473,18 +705,50 @@ impl Vm {
                     self.current_frame_mut().ip += offset as usize;
                 }
                 OpCode::Loop(offset) => {
+                    // the ip already points after the loop instruction, same as for `Jump`
                     self.current_frame_mut().ip -= offset as usize;
-                    // We need to subtract 1 from the ip because the ip will be incremented at the
-                    // beginning of the loop
-                    self.current_frame_mut().ip -= 1;
                 }
                 OpCode::Call(arg_count) => {
-                    if !self.call_value(
+                    self.call_value(
                         self.peek(arg_count).expect("unable to peek value").clone(),
                         arg_count,
-                    ) {
-                        return Err(InterpretError::RuntimeError);
+                    )?;
+                }
+                OpCode::BuildList(count) => {
+                    let start = self.stack.len() - count;
+                    let elements = self.stack.values.split_off(start);
+                    self.push(Value::List(Gc::new(GcCell::new(elements))))?;
+                }
+                OpCode::Index => {
+                    let index = self.pop().expect("unable to pop value");
+                    let target = self.pop().expect("unable to pop value");
+                    let value = match &target {
+                        Value::String(s) => string_char(s, &index),
+                        Value::List(_) => self
+                            .list_slot(&target, &index)
+                            .map(|(list, i)| list.borrow()[i].clone()),
+                        _ => Err("Can only index lists and strings."),
+                    };
+                    let value = match value {
+                        Ok(value) => value,
+                        Err(msg) => {
+                            return Err(self.runtime_error(msg));
+                        }
+                    };
+                    self.push(value)?;
+                }
+                OpCode::IndexSet => {
+                    let value = self.pop().expect("unable to pop value");
+                    let index = self.pop().expect("unable to pop value");
+                    let list = self.pop().expect("unable to pop value");
+                    match self.list_slot(&list, &index) {
+                        Ok((list, i)) => list.borrow_mut()[i] = value.clone(),
+                        Err(msg) => {
+                            return Err(self.runtime_error(msg));
+                        }
                     }
+                    // assignment is an expression, so the assigned value stays on the stack
+                    self.push(value)?;
                 }
                 OpCode::Closure(v) => {
                     let val = &self.current_chunk().constants[v];
@@ -500,23 +764,49 @@ impl Vm {
                             };
                             closure.obj_upvalues.push(obj_upvalue)
                         }
-                        self.push(Value::Closure(Gc::new(closure)));
+                        self.push(Value::Closure(Gc::new(closure)))?;
                     }
                 }
-                _ => {
-                    println!("Unknown operation code during interpreting!");
-                    return Err(InterpretError::RuntimeError);
-                }
             }
         }
     }
 
-    // Enable this function to print the stack
-    // fn print_stack(&self) {
-    //     for value in self.stack.clone() {
-    //         println!("[{}]", value);
-    //     }
-    // }
+    // Check that `list[index]` refers to an existing element and return the list with the index
+    fn list_slot<'v>(
+        &self,
+        list: &'v Value,
+        index: &Value,
+    ) -> Result<(&'v Gc<ObjList>, usize), &'static str> {
+        let list = match list {
+            Value::List(list) => list,
+            _ => return Err("Can only index lists."),
+        };
+        let index = match index {
+            Value::Number(n) if n.fract() == 0.0 => *n,
+            _ => return Err("List index must be an integer."),
+        };
+        if index < 0.0 || index >= list.borrow().len() as f64 {
+            return Err("List index out of bounds.");
+        }
+        Ok((list, index as usize))
+    }
+
+    fn trace_instruction(&mut self, instruction: OpCode) {
+        let mut stack = String::new();
+        for value in self.stack.values.iter() {
+            stack.push_str(&format!("[ {} ]", value));
+        }
+        let ip = self.current_frame().ip;
+        let line = self.current_chunk().lines[ip];
+
+        writeln!(self.trace_output, "          {}", stack).expect("unable to write trace");
+        writeln!(
+            self.trace_output,
+            "{:04} [line {}] {}",
+            ip, line, instruction
+        )
+        .expect("unable to write trace");
+    }
 }
 
 impl Default for Vm {
@@ -525,24 +815,164 @@ impl Default for Vm {
     }
 }
 
-fn clock_native(_args: &[Value]) -> Value {
+fn clock_native(_args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now();
+    let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+    Ok(Value::Number(since_the_epoch.as_secs_f64()))
+}
+
+// now_millis() gives the whole milliseconds since the epoch
+fn now_millis_native(_args: &[Value]) -> Result<Value, String> {
     let now = SystemTime::now();
     let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-    Value::Number(since_the_epoch.as_secs_f64())
+    Ok(Value::Number(since_the_epoch.as_millis() as f64))
+}
+
+// sleep(ms) blocks the whole vm for `ms` milliseconds and returns nil
+fn sleep_native(args: &[Value]) -> Result<Value, String> {
+    // NaN, infinite, negative and too large durations are all rejected by `try_from_secs_f64`
+    let duration = match args {
+        [Value::Number(ms)] => Duration::try_from_secs_f64(ms / 1000.0).ok(),
+        _ => None,
+    };
+    match duration {
+        Some(duration) => {
+            thread::sleep(duration);
+            Ok(Value::Nil)
+        }
+        None => Err("sleep expects a non negative number of milliseconds.".to_string()),
+    }
+}
+
+// push(list, value) appends to the list in place and returns nil
+fn push_native(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [Value::List(list), value] => {
+            list.borrow_mut().push(value.clone());
+            Ok(Value::Nil)
+        }
+        _ => Err("push expects a list and a value.".to_string()),
+    }
+}
+
+// pop(list) removes and returns the last element
+fn pop_native(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [Value::List(list)] => list
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| "Can't pop from an empty list.".to_string()),
+        _ => Err("pop expects a list.".to_string()),
+    }
+}
+
+// substr(s, start, len) copies `len` characters of `s` beginning at `start` into a new string
+fn substr_native(args: &[Value]) -> Result<Value, String> {
+    let (s, start, len) = match args {
+        [Value::String(s), Value::Number(start), Value::Number(len)]
+            if start.fract() == 0.0 && len.fract() == 0.0 =>
+        {
+            (s, *start, *len)
+        }
+        _ => return Err("substr expects a string and two integers.".to_string()),
+    };
+    if start < 0.0 || len < 0.0 || start + len > s.chars().count() as f64 {
+        return Err("Substring out of bounds.".to_string());
+    }
+    let sub: String = s.chars().skip(start as usize).take(len as usize).collect();
+    Ok(Value::String(Gc::new(sub)))
+}
+
+// How `print a, b;` shows each value
+fn print_text(value: &Value) -> String {
+    match value {
+        Value::Function(_)
+        | Value::Closure(_)
+        | Value::String(_)
+        | Value::Number(_)
+        | Value::Bool(_)
+        | Value::Nil
+        | Value::List(_) => value.to_string(),
+        _ => String::from("unknown value"),
+    }
+}
+
+// Lox has no char type, indexing a string gives a new one character string
+fn string_char(s: &str, index: &Value) -> Result<Value, &'static str> {
+    let index = match index {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 => *n as usize,
+        Value::Number(n) if n.fract() == 0.0 => return Err("String index out of bounds."),
+        _ => return Err("String index must be an integer."),
+    };
+    match s.chars().nth(index) {
+        Some(c) => Ok(Value::String(Gc::new(c.to_string()))),
+        None => Err("String index out of bounds."),
+    }
+}
+
+fn len_native(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [Value::List(list)] => Ok(Value::Number(list.borrow().len() as f64)),
+        _ => Err("len expects a list.".to_string()),
+    }
+}
+
+// type_of(v) gives the type of `v` as a string, e.g. "number"
+fn type_of_native(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [value] => Ok(Value::String(Gc::new(value.type_name().to_string()))),
+        _ => Err("type_of expects one argument.".to_string()),
+    }
+}
+
+// assert(cond) is a runtime error when `cond` is falsey, otherwise it returns nil
+fn assert_native(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [cond] if cond.is_falsey() => Err("assertion failed".to_string()),
+        [_] => Ok(Value::Nil),
+        _ => Err("assert expects one argument.".to_string()),
+    }
+}
+
+// assert_eq(a, b) compares like `==`, without the float epsilon of the vm
+fn assert_eq_native(args: &[Value]) -> Result<Value, String> {
+    match args {
+        [a, b] if values_equal(a, b, None) => Ok(Value::Nil),
+        [a, b] => Err(format!("assertion failed: {} != {}", a, b)),
+        _ => Err("assert_eq expects two arguments.".to_string()),
+    }
 }
 
 // unit test
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
     use super::*;
+    use crate::{limits::GC_THRESHOLD, objects::HashKeyString, utils::hash};
+
+    // A writer sharing its buffer so the test can read what the vm traced
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn test_push_pop() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(1.0));
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(1.0)).unwrap();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         assert_eq!(vm.stack.pop(), Some(Value::Number(3.0)));
         assert_eq!(vm.stack.pop(), Some(Value::Number(2.0)));
@@ -553,9 +983,9 @@ mod tests {
     fn test_add() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(1.0));
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(1.0)).unwrap();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         vm.binary_operation(OpCode::Add).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Number(5.0)));
@@ -565,9 +995,9 @@ mod tests {
     fn test_subtract() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(1.0));
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(1.0)).unwrap();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         vm.binary_operation(OpCode::Subtract).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Number(-1.0)));
@@ -577,9 +1007,9 @@ mod tests {
     fn test_multiply() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(1.0));
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(1.0)).unwrap();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         vm.binary_operation(OpCode::Multiply).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Number(6.0)));
@@ -589,18 +1019,54 @@ mod tests {
     fn test_divide() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         vm.binary_operation(OpCode::Divide).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Number(0.6666666666666666)));
     }
 
+    #[test]
+    fn test_modulo() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.stack.push(Value::Number(-7.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
+
+        vm.binary_operation(OpCode::Modulo).unwrap();
+        assert_eq!(vm.stack.pop(), Some(Value::Number(-1.0)));
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        for source in ["var z = 0; 1 / z;", "var z = -0; 1 % z;"] {
+            let mut vm = Vm::new();
+            vm.initialize();
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(message)) => {
+                    assert_eq!("Division by zero. [line 1]", message)
+                }
+                other => panic!("expected a runtime error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_power() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(10.0)).unwrap();
+
+        vm.binary_operation(OpCode::Power).unwrap();
+        assert_eq!(vm.stack.pop(), Some(Value::Number(1024.0)));
+    }
+
     #[test]
     fn test_true() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Bool(true));
+        vm.stack.push(Value::Bool(true)).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Bool(true)));
     }
 
@@ -608,7 +1074,7 @@ mod tests {
     fn test_false() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Bool(false));
+        vm.stack.push(Value::Bool(false)).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Bool(false)));
     }
 
@@ -616,7 +1082,9 @@ mod tests {
     fn test_string() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::String(Gc::new("hello".to_string())));
+        vm.stack
+            .push(Value::String(Gc::new("hello".to_string())))
+            .unwrap();
         assert_eq!(
             vm.stack.pop(),
             Some(Value::String(Gc::new("hello".to_string())))
@@ -627,9 +1095,9 @@ mod tests {
     fn test_less() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(1.0));
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(1.0)).unwrap();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         vm.binary_operation(OpCode::Less).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Bool(true)));
@@ -639,11 +1107,545 @@ mod tests {
     fn test_greater() {
         let mut vm = Vm::new();
         vm.initialize();
-        vm.stack.push(Value::Number(1.0));
-        vm.stack.push(Value::Number(2.0));
-        vm.stack.push(Value::Number(3.0));
+        vm.stack.push(Value::Number(1.0)).unwrap();
+        vm.stack.push(Value::Number(2.0)).unwrap();
+        vm.stack.push(Value::Number(3.0)).unwrap();
 
         vm.binary_operation(OpCode::Greater).unwrap();
         assert_eq!(vm.stack.pop(), Some(Value::Bool(false)));
     }
+
+    fn run_code(code: Vec<OpCode>, constants: Vec<Value>) -> Value {
+        let mut function = ObjFunction::new(String::from("script"));
+        for instruction in code {
+            function.chunk.write_to_chunk(instruction, 1);
+        }
+        function.chunk.constants = constants;
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.run_script(function).unwrap()
+    }
+
+    #[test]
+    fn test_deep_recursion_overflows_stack() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        // every call keeps the callee, its argument and two locals, 40 calls need 160 slots
+        vm.set_stack_max(100);
+        let source = r#"
+        fun f(n) {
+            var a = n;
+            var b = n;
+            if (n == 40) {
+                return n;
+            }
+            return f(n + 1);
+        }
+        f(0);
+        "#;
+        assert!(matches!(
+            vm.interpret(source),
+            Err(InterpretError::RuntimeError(_))
+        ));
+
+        vm.reset();
+        vm.set_stack_max(STACK_MAX);
+        assert!(vm.interpret(source).is_ok());
+    }
+
+    #[test]
+    fn test_jump_if_false_keeps_condition() {
+        // the jump is taken and the falsey value is still there to return
+        let code = vec![
+            OpCode::Nil,
+            OpCode::JumpIfFalse(1),
+            OpCode::Pop,
+            OpCode::Return,
+        ];
+        assert_eq!(Value::Nil, run_code(code, vec![]));
+        // not taken, the Pop drops the condition
+        let code = vec![
+            OpCode::Constant(0),
+            OpCode::True,
+            OpCode::JumpIfFalse(0),
+            OpCode::Pop,
+            OpCode::Return,
+        ];
+        assert_eq!(Value::Number(0.0), run_code(code, vec![Value::Number(0.0)]));
+    }
+
+    #[test]
+    fn test_jump_if_true() {
+        // taken, the truthy value is returned
+        let code = vec![
+            OpCode::Constant(0),
+            OpCode::JumpIfTrue(2),
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_eq!(Value::Number(0.0), run_code(code, vec![Value::Number(0.0)]));
+        // not taken, false is popped and replaced
+        let code = vec![
+            OpCode::False,
+            OpCode::JumpIfTrue(2),
+            OpCode::Pop,
+            OpCode::Constant(0),
+            OpCode::Return,
+        ];
+        assert_eq!(Value::Number(1.0), run_code(code, vec![Value::Number(1.0)]));
+    }
+
+    #[test]
+    fn test_nil_equals_nil() {
+        let code = vec![OpCode::Nil, OpCode::Nil, OpCode::Equal, OpCode::Return];
+        assert_eq!(Value::Bool(true), run_code(code, vec![]));
+    }
+
+    #[test]
+    fn test_number_not_equal_nil() {
+        let code = vec![
+            OpCode::Constant(0),
+            OpCode::Nil,
+            OpCode::Equal,
+            OpCode::Return,
+        ];
+        assert_eq!(Value::Bool(false), run_code(code, vec![Value::Number(1.0)]));
+        let code = vec![
+            OpCode::Constant(0),
+            OpCode::Nil,
+            OpCode::NotEqual,
+            OpCode::Return,
+        ];
+        assert_eq!(Value::Bool(true), run_code(code, vec![Value::Number(1.0)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "unable to pop value")]
+    fn test_equal_on_underflow_panics() {
+        // the only value on the stack is the script itself
+        run_code(vec![OpCode::Equal, OpCode::Return], vec![]);
+    }
+
+    #[test]
+    fn test_trace() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.trace_output = Box::new(buffer.clone());
+        vm.set_trace(true);
+        // a literal `1 + 2` is folded by the compiler
+        vm.interpret("var a = 1; a + 2;").unwrap();
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert!(trace.contains("constant"));
+        assert!(trace.contains("add operation"));
+        assert!(trace.contains("system return"));
+    }
+
+    #[test]
+    fn test_trace_off_by_default() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.trace_output = Box::new(buffer.clone());
+        vm.interpret("1 + 2;").unwrap();
+
+        assert!(buffer.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_reset_after_runtime_error() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        assert!(vm
+            .interpret("fun foo() { return 1 + true; } foo();")
+            .is_err());
+        vm.reset();
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+        assert!(vm.interpret("1 + 2;").is_ok());
+    }
+
+    #[test]
+    fn test_globals_persist_across_interpret() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.interpret("var a = 1;").unwrap();
+        vm.interpret("var b = a + 1;").unwrap();
+
+        let key = HashKeyString {
+            hash: hash("b"),
+            value: "b".to_string(),
+        };
+        assert_eq!(vm.table.get(&key), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_globals_persist_across_reset() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.interpret("var a = 1;").unwrap();
+        assert!(vm.interpret("a + true;").is_err());
+        vm.reset();
+        vm.interpret("a = a + 1;").unwrap();
+
+        let key = HashKeyString {
+            hash: hash("a"),
+            value: "a".to_string(),
+        };
+        assert_eq!(vm.table.get(&key), Some(&Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_interpret_repl_echo() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        assert_eq!(
+            vm.interpret_repl("1 + 1").unwrap(),
+            Some(Value::Number(2.0))
+        );
+        assert_eq!(
+            vm.interpret_repl("1 + 2;").unwrap(),
+            Some(Value::Number(3.0))
+        );
+    }
+
+    #[test]
+    fn test_interpret_repl_no_echo() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        assert_eq!(vm.interpret_repl("var a = 1;").unwrap(), None);
+        assert_eq!(vm.interpret_repl("print a;").unwrap(), None);
+        assert_eq!(vm.interpret_repl("a").unwrap(), Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_const_globals_across_scripts() {
+        let mut vm = Vm::new();
+        vm.interpret("const a = 1;").unwrap();
+        assert!(matches!(
+            vm.interpret("a = 2;"),
+            Err(InterpretError::CompileError(_))
+        ));
+        assert!(matches!(
+            vm.interpret_repl("var a = 3;"),
+            Err(InterpretError::CompileError(_))
+        ));
+        assert_eq!(vm.interpret_repl("a").unwrap(), Some(Value::Number(1.0)));
+    }
+
+    fn answer_native(_: &[Value]) -> Result<Value, String> {
+        Ok(Value::Number(42.0))
+    }
+
+    fn fail_native(_: &[Value]) -> Result<Value, String> {
+        Err(String::from("Host failure."))
+    }
+
+    #[test]
+    fn test_now_millis_is_whole() {
+        match now_millis_native(&[]) {
+            Ok(Value::Number(ms)) => assert_eq!(0.0, ms.fract()),
+            other => panic!("expected a number, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sleep_rejects_negative() {
+        assert_eq!(Ok(Value::Nil), sleep_native(&[Value::Number(0.0)]));
+        assert!(sleep_native(&[Value::Number(-1.0)]).is_err());
+        assert!(sleep_native(&[Value::Nil]).is_err());
+        assert!(sleep_native(&[Value::Number(1e300)]).is_err());
+        assert!(sleep_native(&[Value::Number(f64::NAN)]).is_err());
+    }
+
+    #[test]
+    fn test_pop_n() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        let mut function = ObjFunction::new(String::from("script"));
+        for instruction in [
+            OpCode::True,
+            OpCode::Nil,
+            OpCode::Nil,
+            OpCode::Nil,
+            OpCode::PopN(3),
+            OpCode::Return,
+        ] {
+            function.chunk.write_to_chunk(instruction, 1);
+        }
+        assert_eq!(Value::Bool(true), vm.run_script(function).unwrap());
+        // only the script closure is left
+        assert_eq!(1, vm.stack.len());
+    }
+
+    #[test]
+    fn test_gc_threshold() {
+        let source = r#"
+        var s = "";
+        for (var i = 0; i < 500; i = i + 1) {
+            s = "a" + "b";
+        }
+        "#;
+        let collections = |threshold: usize| {
+            let mut vm = Vm::with_gc_threshold(threshold);
+            vm.initialize();
+            let before = rox_gc::stats().collections_perfomed;
+            vm.interpret(source).unwrap();
+            rox_gc::stats().collections_perfomed - before
+        };
+
+        assert!(collections(GC_THRESHOLD) < collections(100));
+    }
+
+    #[test]
+    fn test_new_keeps_gc_config() {
+        let source = r#"
+        var s = "";
+        for (var i = 0; i < 500; i = i + 1) {
+            s = "a" + "b";
+        }
+        "#;
+        let _embedder = Vm::with_gc_threshold(usize::MAX);
+        // a later vm on the same thread must not bring the collections back
+        let mut vm = Vm::new();
+        vm.initialize();
+        let before = rox_gc::stats().collections_perfomed;
+        vm.interpret(source).unwrap();
+        assert_eq!(before, rox_gc::stats().collections_perfomed);
+    }
+
+    #[test]
+    fn test_register_native() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.register_native("answer", answer_native);
+        assert_eq!(
+            vm.interpret_repl("answer() + 1").unwrap(),
+            Some(Value::Number(43.0))
+        );
+    }
+
+    #[test]
+    fn test_runtime_error_message() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        match vm.interpret("var a = 1;\nprint a + missing;") {
+            Err(InterpretError::RuntimeError(message)) => {
+                assert_eq!("undefined variable 'missing' [line 2]", message)
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compile_error_message() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        match vm.interpret("print ;\nvar 1;") {
+            Err(InterpretError::CompileError(message)) => assert_eq!(
+                "[line 1:7] Error at ';': Expect expression.\n[line 2:5] Error at '1': Expect variable name.",
+                message
+            ),
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_register_native_error() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.register_native("fail", fail_native);
+        assert!(matches!(
+            vm.interpret_repl("fail();"),
+            Err(InterpretError::RuntimeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_float_equality_exact_by_default() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        assert_eq!(
+            vm.interpret_repl("0.1 + 0.2 == 0.3").unwrap(),
+            Some(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn test_float_equality_with_epsilon() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        vm.set_float_epsilon(Some(1e-9));
+        assert_eq!(
+            vm.interpret_repl("0.1 + 0.2 == 0.3").unwrap(),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            vm.interpret_repl("0.1 + 0.2 == 0.4").unwrap(),
+            Some(Value::Bool(false))
+        );
+
+        vm.set_float_epsilon(None);
+        assert_eq!(
+            vm.interpret_repl("0.1 + 0.2 == 0.3").unwrap(),
+            Some(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn test_optimized_program() {
+        let source = r#"
+        fun count(n) {
+            var total = 0;
+            for (var i = 0; i <= n; i = i + 1) {
+                var a = i;
+                var b = a;
+                if (b >= 2 and b != 4) {
+                    total = total + b;
+                }
+            }
+            return total;
+        }
+        count(5)
+        "#;
+        for optimize in [false, true] {
+            let mut vm = Vm::new();
+            vm.initialize();
+            vm.set_optimize(optimize);
+            // 2 + 3 + 5
+            assert_eq!(
+                vm.interpret_repl(source).unwrap(),
+                Some(Value::Number(10.0))
+            );
+        }
+    }
+
+    #[test]
+    fn test_optimized_peephole() {
+        let source = r#"
+        var a = "a";
+        1;
+        print - -2;
+        print !!(1 < 2);
+        print !!nil;
+        print !!a;
+        if (a == "a") print 3;
+        "#;
+        let mut outputs = Vec::new();
+        for optimize in [false, true] {
+            let mut vm = Vm::new();
+            vm.initialize();
+            vm.set_optimize(optimize);
+            outputs.push(vm.interpret_capture(source).unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(
+            "Printing value of 2\nPrinting value of true\nPrinting value of false\nPrinting value of true\nPrinting value of 3\n",
+            outputs[1]
+        );
+    }
+
+    #[test]
+    fn test_interpret_after_runtime_error() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        let source = r#"
+        fun foo() {
+            var x = 1 + true;
+            print "after";
+            return 1;
+        }
+        foo();
+        print "script";
+        "#;
+        assert!(vm.interpret(source).is_err());
+        // no reset in between, the frames of the failed run must not be resumed
+        assert_eq!(
+            "Printing value of 1\n",
+            vm.interpret_capture("print 1;").unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_globals_hashed_once() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        let source = r#"
+        var i = 0;
+        var total = 0;
+        while (i < 100) {
+            total = total + i;
+            i = i + 1;
+        }
+        var total = total + 1;
+        "#;
+        let function = vm.compile(source.as_bytes()).unwrap();
+        let before = crate::utils::hash_calls();
+        vm.run_script(function).unwrap();
+        // every name was hashed by the compiler, the loop and the redefinition never hash again
+        assert_eq!(before, crate::utils::hash_calls());
+        assert_eq!(
+            Some(&Value::Number(4951.0)),
+            vm.table.get(&HashKeyString {
+                hash: hash("total"),
+                value: String::from("total"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_set_global() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        let output = vm
+            .interpret_capture("var a = 1; a = 2; a = a + 1; print a;")
+            .unwrap();
+        assert_eq!("Printing value of 3\n", output);
+
+        vm.reset();
+        match vm.interpret("b = 1;") {
+            Err(InterpretError::RuntimeError(message)) => {
+                assert_eq!("undefined variable 'b' [line 1]", message)
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_interpret_capture() {
+        let mut vm = Vm::new();
+        assert_eq!(
+            "Printing value of 3\n",
+            vm.interpret_capture("print 1 + 2;").unwrap()
+        );
+        assert_eq!(
+            "Printing value of a 1\nnil\n<fn f/0>\n",
+            vm.interpret_capture(r#"print "a", 1; print nil; fun f() {} print f;"#)
+                .unwrap()
+        );
+        // nothing is captured once it returned
+        assert!(vm.captured.is_none());
+        assert!(vm.interpret_capture("print 1; print nil + 1;").is_err());
+        assert!(vm.captured.is_none());
+    }
+
+    #[test]
+    fn test_runtime_error_before_first_instruction() {
+        let mut vm = Vm::new();
+        vm.initialize();
+        let mut function = ObjFunction::new(String::from("f"));
+        function.chunk.write_to_chunk(OpCode::Nil, 3);
+        let frame = CallFrame::new(ObjClosure::new(function));
+        assert_eq!(3, frame.line());
+        vm.frames.push(frame);
+        // the frame never ran an instruction, the trace must not underflow its ip
+        vm.runtime_error("boom");
+
+        // a frame without any instruction has no line at all
+        let frame = CallFrame::new(ObjClosure::new(ObjFunction::new(String::from("g"))));
+        assert_eq!(0, frame.line());
+    }
 }
@@ -1,62 +1,123 @@
 use assert_cmd::prelude::*;
 use std::io::Write;
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
 use tempfile::NamedTempFile;
 
 type TestResult = Result<(), Box<dyn std::error::Error>>;
 
-fn run_test_contains(input: &str, expected: &str) -> TestResult {
+// Run `input` as a script file, both outputs are printed so a failing test shows them
+fn run_script(input: &str) -> Result<Output, Box<dyn std::error::Error>> {
     let mut file = NamedTempFile::new()?;
-    let name = file.path();
+    writeln!(file, "{}", input)?;
 
-    let mut cmd = Command::cargo_bin("rox")?;
-    cmd.arg(name);
+    let output = Command::cargo_bin("rox")?
+        .arg(file.path())
+        .env(
+            "PWD",
+            std::env::current_dir().expect("Can't get current dir"),
+        )
+        .output()?;
+    print_output(&output);
 
-    writeln!(file, "{}", input)?;
+    Ok(output)
+}
 
-    let output = cmd.output()?;
+// Same as `run_script` but `input` is fed to the REPL through stdin
+fn run_repl(input: &str) -> Result<Output, Box<dyn std::error::Error>> {
+    let mut child = Command::cargo_bin("rox")?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
 
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    child
+        .stdin
+        .take()
+        .ok_or("Can't open stdin")?
+        .write_all(input.as_bytes())?;
 
-    println!("stdout: {}", stdout);
-    println!("stderr: {}", stderr);
+    let output = child.wait_with_output()?;
+    print_output(&output);
+
+    Ok(output)
+}
+
+fn print_output(output: &Output) {
+    println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
+    println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+}
+
+fn run_test_contains(input: &str, expected: &str) -> TestResult {
+    let output = run_script(input)?;
 
     assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(expected));
 
-    assert!(stdout.contains(expected));
+    Ok(())
+}
+
+// Unlike `run_test_contains`, the trimmed stdout must match `expected` exactly, so an output of
+// "100" does not pass a check for "1"
+fn run_test_eq(input: &str, expected: &str) -> TestResult {
+    let output = run_script(input)?;
+
+    assert!(output.status.success());
+    assert_eq!(expected, String::from_utf8_lossy(&output.stdout).trim());
 
     Ok(())
 }
 
-pub fn fail_test(input: &str, expected: &str) -> TestResult {
-    let mut file = NamedTempFile::new()?;
-    let name = file.path();
+// Run `input` as a script and check the process exit code
+fn exit_code_test(input: &str, expected: i32) -> TestResult {
+    let output = run_script(input)?;
 
-    let mut cmd = Command::cargo_bin("rox")?;
-    cmd.arg(name);
-    cmd.env(
-        "PWD",
-        std::env::current_dir().expect("Can't get current dir"),
-    );
+    assert_eq!(Some(expected), output.status.code());
 
-    writeln!(file, "{}", input)?;
+    Ok(())
+}
 
-    let output = cmd.output()?;
+// Feed `input` to the REPL through stdin, the session must survive errors and exit cleanly on EOF
+fn run_repl_test(input: &str, expected: &str) -> TestResult {
+    let output = run_repl(input)?;
 
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(expected));
 
-    println!("stdout: {}", stdout);
-    println!("stderr: {}", stderr);
+    Ok(())
+}
+
+// The program must still run successfully, `expected` is looked up in the warnings on stderr
+fn warning_test(input: &str, expected: &str) -> TestResult {
+    let output = run_script(input)?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success());
+    if expected.is_empty() {
+        assert!(stderr.is_empty());
+    } else {
+        assert!(stderr.contains(expected));
+    }
+
+    Ok(())
+}
+
+pub fn fail_test(input: &str, expected: &str) -> TestResult {
+    let output = run_script(input)?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
 
     assert!(!stderr.is_empty() && stderr.contains(expected));
 
     Ok(())
 }
+
 #[test]
 fn rox_constant_number() -> TestResult {
-    run_test_contains("print 1;", "1")
+    run_test_eq("print 1;", "Printing value of 1")
+}
+
+#[test]
+fn rox_constant_number_exact() -> TestResult {
+    run_test_eq("print 100;", "Printing value of 100")
 }
 
 #[test]
@@ -83,6 +144,24 @@ fn rox_arithmetic_grouping() -> TestResult {
     run_test_contains("print (1+2)*3;", "9")
 }
 
+#[test]
+fn rox_arithmetic_exact() -> TestResult {
+    run_test_eq("print 10 - 2 * 3;", "Printing value of 4")
+}
+
+#[test]
+fn rox_arithmetic_exact_division() -> TestResult {
+    run_test_eq("print 1 / 4;", "Printing value of 0.25")
+}
+
+#[test]
+fn rox_arithmetic_exact_multiple_lines() -> TestResult {
+    run_test_eq(
+        "print 1 + 1;\nprint 2 * 5;",
+        "Printing value of 2\nPrinting value of 10",
+    )
+}
+
 #[test]
 fn rox_arithmetic_complex_grouping() -> TestResult {
     run_test_contains("print -((1+2)*2);", "-6")
@@ -159,7 +238,7 @@ fn rox_compare_group_equal() -> TestResult {
 
 #[test]
 fn rox_nil() -> TestResult {
-    run_test_contains("print nil;", "Nil")
+    run_test_eq("print nil;", "nil")
 }
 #[test]
 fn rox_nagative_string() -> TestResult {
@@ -232,7 +311,7 @@ fn rox_falsey_nil3() -> TestResult {
 
 #[test]
 fn rox_variable() -> TestResult {
-    run_test_contains("var a = 1;", "1")
+    run_test_eq("var a = 1; print a;", "Printing value of 1")
 }
 
 #[test]
@@ -743,7 +822,7 @@ fn rox_for() -> TestResult {
             }
             print x;
         "#,
-        "5",
+        "Printing value of 11",
     )
 }
 
@@ -1035,7 +1114,7 @@ fn rox_native_func() -> TestResult {
         r#"
             print clock();
         "#,
-        "clock",
+        "Printing value of",
     )
 }
 
@@ -1115,3 +1194,1456 @@ fn rox_closure_with_param() -> TestResult {
         "4",
     )
 }
+
+#[test]
+fn rox_repl_runtime_error_continues() -> TestResult {
+    run_repl_test("print a;\nprint 1 + 2;\n", "Printing value of 3")
+}
+
+#[test]
+fn rox_repl_compile_error_continues() -> TestResult {
+    run_repl_test("print 1 +;\nprint 1 + 2;\n", "Printing value of 3")
+}
+
+#[test]
+fn rox_repl_error_inside_function_continues() -> TestResult {
+    run_repl_test(
+        "fun foo() { return 1 + true; }\nfoo();\nprint 2 + 2;\n",
+        "Printing value of 4",
+    )
+}
+
+#[test]
+fn rox_unreachable_after_return() -> TestResult {
+    warning_test(
+        r#"
+            fun f() {
+                return 1;
+                print 2;
+            }
+            print f();
+        "#,
+        "[line 4:17] Warning: Unreachable code.",
+    )
+}
+
+#[test]
+fn rox_unreachable_after_exhaustive_if() -> TestResult {
+    warning_test(
+        r#"
+            fun f(x) {
+                if (x) {
+                    return 1;
+                } else {
+                    return 2;
+                }
+                print 3;
+            }
+            print f(true);
+        "#,
+        "[line 8:17] Warning: Unreachable code.",
+    )
+}
+
+#[test]
+fn rox_reachable_after_partial_if() -> TestResult {
+    warning_test(
+        r#"
+            fun f(x) {
+                if (x) {
+                    return 1;
+                }
+                print 3;
+            }
+            f(false);
+        "#,
+        "",
+    )
+}
+
+#[test]
+fn rox_repl_multi_line_function() -> TestResult {
+    run_repl_test(
+        "fun foo(x) {\n  return x + 1;\n}\nprint foo(1);\n",
+        "Printing value of 2",
+    )
+}
+
+#[test]
+fn rox_repl_continuation_prompt() -> TestResult {
+    run_repl_test("print (1 +\n2);\n", "... Printing value of 3")
+}
+
+#[test]
+fn rox_identical_alias() -> TestResult {
+    run_test_eq(
+        r#"
+            var a = "a" + "b";
+            var b = a;
+            print a == b;
+            print a === b;
+        "#,
+        "Printing value of true\nPrinting value of true",
+    )
+}
+
+#[test]
+fn rox_identical_distinct_objects() -> TestResult {
+    run_test_eq(
+        r#"
+            var a = "a" + "b";
+            var b = "a" + "b";
+            print a == b;
+            print a === b;
+        "#,
+        "Printing value of true\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_identical_numbers() -> TestResult {
+    run_test_eq("print 1 === 1;", "Printing value of true")
+}
+
+#[test]
+fn rox_identical_functions() -> TestResult {
+    run_test_eq(
+        r#"
+            fun foo() {}
+            fun bar() {}
+            var f = foo;
+            print f === foo;
+            print foo === bar;
+        "#,
+        "Printing value of true\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_repl_globals_persist() -> TestResult {
+    run_repl_test("var a = 1;\nprint a + 1;\n", "Printing value of 2")
+}
+
+#[test]
+fn rox_repl_globals_persist_after_error() -> TestResult {
+    run_repl_test(
+        "var a = 1;\nprint a + nil;\nprint a + 1;\n",
+        "Printing value of 2",
+    )
+}
+
+#[test]
+fn rox_exit_code_success() -> TestResult {
+    exit_code_test("print 1;", 0)
+}
+
+#[test]
+fn rox_exit_code_compile_error() -> TestResult {
+    exit_code_test("print 1 +;", 65)
+}
+
+#[test]
+fn rox_exit_code_runtime_error() -> TestResult {
+    exit_code_test("print 1 + nil;", 70)
+}
+
+#[test]
+fn rox_exit_code_usage_error() -> TestResult {
+    let output = Command::cargo_bin("rox")?
+        .args(["a.lox", "b.lox"])
+        .output()?;
+
+    assert_eq!(Some(64), output.status.code());
+
+    Ok(())
+}
+
+#[test]
+fn rox_version() -> TestResult {
+    let output = Command::cargo_bin("rox")?.arg("--version").output()?;
+
+    assert!(output.status.success());
+    assert_eq!(
+        format!("rox {}", env!("CARGO_PKG_VERSION")),
+        String::from_utf8_lossy(&output.stdout).trim()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rox_help() -> TestResult {
+    let output = Command::cargo_bin("rox")?.arg("--help").output()?;
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Usage: rox"));
+
+    Ok(())
+}
+
+#[test]
+fn rox_unknown_flag() -> TestResult {
+    let output = Command::cargo_bin("rox")?
+        .args(["--bogus", "a.lox"])
+        .output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    assert_eq!(Some(64), output.status.code());
+    assert!(stderr.contains("rox: unknown option '--bogus'"));
+    assert!(stderr.contains("Usage: rox"));
+    assert!(output.stdout.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn rox_exit_code_io_error() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let output = Command::cargo_bin("rox")?
+        .arg(dir.path().join("missing.lox"))
+        .output()?;
+
+    assert_eq!(Some(74), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Could not read file"));
+
+    Ok(())
+}
+
+#[test]
+fn rox_top_level_return() -> TestResult {
+    fail_test("return;", "Can't return from top-level code.")
+}
+
+#[test]
+fn rox_top_level_return_value() -> TestResult {
+    fail_test("return 1;", "Can't return from top-level code.")
+}
+
+#[test]
+fn rox_call_wrong_arity() -> TestResult {
+    fail_test(
+        r#"
+fun add(a, b) {
+    return a + b;
+}
+add(1);
+"#,
+        "Runtime error: Expected 2 arguments but got 1. [line 5]",
+    )
+}
+
+#[test]
+fn rox_call_non_callable() -> TestResult {
+    fail_test(
+        r#"
+var a = 1;
+a();
+"#,
+        "Runtime error: Can only call functions and classes, got number. [line 3]",
+    )
+}
+
+#[test]
+fn rox_list_construction() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [1, "two", true, nil];
+print xs;
+"#,
+        "Printing value of [1, two, true, nil]",
+    )
+}
+
+#[test]
+fn rox_list_empty() -> TestResult {
+    run_test_eq("print [];", "Printing value of []")
+}
+
+#[test]
+fn rox_list_read() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [1, 2, 3];
+print xs[0] + xs[2];
+"#,
+        "Printing value of 4",
+    )
+}
+
+#[test]
+fn rox_list_write() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [1, 2, 3];
+xs[1] = 9;
+print xs;
+"#,
+        "Printing value of [1, 9, 3]",
+    )
+}
+
+#[test]
+fn rox_list_write_shared() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [1, 2];
+var ys = xs;
+ys[0] = 5;
+print xs[0];
+"#,
+        "Printing value of 5",
+    )
+}
+
+#[test]
+fn rox_list_nested() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [[1, 2], [3, 4]];
+xs[1][0] = 7;
+print xs[1][0];
+"#,
+        "Printing value of 7",
+    )
+}
+
+#[test]
+fn rox_list_identical() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [1];
+var ys = xs;
+print xs === ys;
+print xs === [1];
+print xs == [1];
+"#,
+        "Printing value of true\nPrinting value of false\nPrinting value of true",
+    )
+}
+
+#[test]
+fn rox_list_out_of_bounds() -> TestResult {
+    fail_test(
+        r#"
+var xs = [1, 2, 3];
+print xs[3];
+"#,
+        "Runtime error: List index out of bounds. [line 3]",
+    )
+}
+
+#[test]
+fn rox_list_write_out_of_bounds() -> TestResult {
+    fail_test(
+        r#"
+var xs = [1, 2, 3];
+xs[-1] = 0;
+"#,
+        "Runtime error: List index out of bounds. [line 3]",
+    )
+}
+
+#[test]
+fn rox_list_index_non_list() -> TestResult {
+    fail_test(
+        r#"
+var a = 1;
+print a[0];
+"#,
+        "Runtime error: Can only index lists and strings. [line 3]",
+    )
+}
+
+#[test]
+fn rox_repl_echo_expression() -> TestResult {
+    run_repl_test("var a = 1;\na + 1;\n", "> > 2\n")
+}
+
+#[test]
+fn rox_for_no_condition_no_increment() -> TestResult {
+    run_test_eq(
+        r#"
+var i = 0;
+for (;;) {
+    i = i + 1;
+    if (i == 3) break;
+}
+print i;
+"#,
+        "Printing value of 3",
+    )
+}
+
+#[test]
+fn rox_for_condition_only() -> TestResult {
+    run_test_eq(
+        r#"
+var i = 0;
+var c = true;
+for (; c;) {
+    i = i + 1;
+    c = i < 4;
+}
+print i;
+"#,
+        "Printing value of 4",
+    )
+}
+
+#[test]
+fn rox_for_increment_only() -> TestResult {
+    run_test_eq(
+        r#"
+var i = 0;
+for (;; i = i + 1) {
+    if (i == 5) break;
+}
+print i;
+"#,
+        "Printing value of 5",
+    )
+}
+
+#[test]
+fn rox_for_full_form() -> TestResult {
+    run_test_eq(
+        r#"
+var sum = 0;
+for (var i = 0; i < 4; i = i + 1) {
+    sum = sum + i;
+}
+print sum;
+"#,
+        "Printing value of 6",
+    )
+}
+
+#[test]
+fn rox_for_leaves_stack_balanced() -> TestResult {
+    // locals declared after the loop must still resolve to the right stack slots
+    run_test_eq(
+        r#"
+fun f() {
+    for (var i = 0; i < 2; i = i + 1) {}
+    var a = "after";
+    return a;
+}
+print f();
+"#,
+        "Printing value of after",
+    )
+}
+
+#[test]
+fn rox_while_break() -> TestResult {
+    run_test_eq(
+        r#"
+var i = 0;
+while (true) {
+    var step = 2;
+    i = i + step;
+    if (i > 5) break;
+}
+print i;
+"#,
+        "Printing value of 6",
+    )
+}
+
+#[test]
+fn rox_break_pops_body_locals() -> TestResult {
+    run_test_eq(
+        r#"
+fun f() {
+    var a = "a";
+    for (var i = 0; i < 3; i = i + 1) {
+        var b = "b";
+        break;
+    }
+    var c = "c";
+    return a + c;
+}
+print f();
+"#,
+        "Printing value of ac",
+    )
+}
+
+#[test]
+fn rox_break_outside_loop() -> TestResult {
+    fail_test("break;", "Can't use 'break' outside of a loop.")
+}
+
+#[test]
+fn rox_list_push_pop_len() -> TestResult {
+    run_test_eq(
+        r#"
+var xs = [1];
+push(xs, 2);
+push(xs, 3);
+print pop(xs);
+print len(xs);
+print xs;
+"#,
+        "Printing value of 3\nPrinting value of 2\nPrinting value of [1, 2]",
+    )
+}
+
+#[test]
+fn rox_list_pop_empty() -> TestResult {
+    fail_test(
+        r#"
+var xs = [];
+pop(xs);
+"#,
+        "Runtime error: Can't pop from an empty list. [line 3]",
+    )
+}
+
+#[test]
+fn rox_list_len_non_list() -> TestResult {
+    fail_test("len(1);", "Runtime error: len expects a list.")
+}
+
+#[test]
+fn rox_for_forever_keeps_first_instruction() -> TestResult {
+    // an absent condition used to patch instruction 0, which is the first print here
+    run_test_eq(
+        r#"
+print "start";
+var i = 0;
+for (;;) { i = i + 1; if (i == 3) break; }
+print i;
+"#,
+        "Printing value of start\nPrinting value of 3",
+    )
+}
+
+#[test]
+fn rox_substr() -> TestResult {
+    run_test_eq(r#"print substr("hello", 1, 3);"#, "Printing value of ell")
+}
+
+#[test]
+fn rox_substr_whole_and_empty() -> TestResult {
+    run_test_eq(
+        r#"
+print substr("hello", 0, 5);
+print substr("hello", 5, 0) == "";
+"#,
+        "Printing value of hello\nPrinting value of true",
+    )
+}
+
+#[test]
+fn rox_substr_out_of_range() -> TestResult {
+    fail_test(
+        r#"substr("hello", 3, 5);"#,
+        "Runtime error: Substring out of bounds. [line 1]",
+    )
+}
+
+#[test]
+fn rox_string_index() -> TestResult {
+    run_test_eq(
+        r#"
+var s = "hello";
+print s[0] + s[4];
+"#,
+        "Printing value of ho",
+    )
+}
+
+#[test]
+fn rox_string_index_out_of_range() -> TestResult {
+    fail_test(
+        r#"
+var s = "hello";
+print s[5];
+"#,
+        "Runtime error: String index out of bounds. [line 3]",
+    )
+}
+
+#[test]
+fn rox_lambda_assigned() -> TestResult {
+    run_test_eq(
+        r#"
+var f = fun(x) { return x + 1; };
+print f(2);
+"#,
+        "Printing value of 3",
+    )
+}
+
+#[test]
+fn rox_lambda_as_argument() -> TestResult {
+    run_test_eq(
+        r#"
+fun apply(f, x) {
+    return f(x);
+}
+print apply(fun(x) { return x * 2; }, 4);
+"#,
+        "Printing value of 8",
+    )
+}
+
+#[test]
+fn rox_lambda_closure() -> TestResult {
+    run_test_eq(
+        r#"
+fun adder(n) {
+    return fun(x) { return x + n; };
+}
+var add2 = adder(2);
+print add2(5);
+"#,
+        "Printing value of 7",
+    )
+}
+
+#[test]
+fn rox_lambda_name() -> TestResult {
+    run_test_eq("print fun() {};", "<fn lambda/0>")
+}
+
+#[test]
+fn rox_while_at_start_of_script() -> TestResult {
+    run_test_eq("while (false) {}\nprint 1;", "Printing value of 1")
+}
+
+#[test]
+fn rox_for_at_start_of_script() -> TestResult {
+    run_test_eq("for (;;) { break; }\nprint 1;", "Printing value of 1")
+}
+
+#[test]
+fn rox_string_escapes() -> TestResult {
+    run_test_eq(r#"print "a\tb\\c\"d";"#, "Printing value of a\tb\\c\"d")
+}
+
+#[test]
+fn rox_string_newline_escape() -> TestResult {
+    run_test_eq(r#"print "a\nb";"#, "Printing value of a\nb")
+}
+
+#[test]
+fn rox_string_hex_escape() -> TestResult {
+    run_test_eq(r#"print "\x41\x62";"#, "Printing value of Ab")
+}
+
+#[test]
+fn rox_string_hex_escape_utf8() -> TestResult {
+    run_test_eq(r#"print "\xc3\xa9";"#, "Printing value of é")
+}
+
+#[test]
+fn rox_string_unicode_escape() -> TestResult {
+    run_test_eq(r#"print "\u{41}";"#, "Printing value of A")
+}
+
+#[test]
+fn rox_string_unicode_escape_multibyte() -> TestResult {
+    run_test_eq(r#"print "\u{1F600}" == "😀";"#, "Printing value of true")
+}
+
+#[test]
+fn rox_string_unicode_escape_out_of_range() -> TestResult {
+    fail_test(
+        r#"print "\u{110000}";"#,
+        "Invalid unicode code point in '\\u{...}'.",
+    )
+}
+
+#[test]
+fn rox_string_invalid_utf8_escape() -> TestResult {
+    fail_test(r#"print "\xff";"#, "String escapes must form valid UTF-8.")
+}
+
+#[test]
+fn rox_string_malformed_hex_escape() -> TestResult {
+    fail_test(r#"print "\x4";"#, "Expect two hex digits after '\\x'.")
+}
+
+#[test]
+fn rox_string_unknown_escape() -> TestResult {
+    fail_test(r#"print "\q";"#, "Invalid escape sequence '\\q'.")
+}
+
+#[test]
+fn rox_error_column_on_second_line() -> TestResult {
+    fail_test(
+        "print 1;\nprint 1 +;",
+        "[line 2:10] Error at ';': Expect expression.",
+    )
+}
+
+#[test]
+fn rox_error_unexpected_character() -> TestResult {
+    fail_test(
+        "print 1 @ 2;",
+        "[line 1:9] Error: Unexpected character '@'.",
+    )
+}
+
+#[test]
+fn rox_error_unterminated_string() -> TestResult {
+    fail_test(
+        "print 1;\nprint \"ab",
+        "[line 2:7] Error: Unterminated string.",
+    )
+}
+
+#[test]
+fn rox_error_at_end() -> TestResult {
+    fail_test("print 1", "[line 2:1] Error at end: Expect ';' after value")
+}
+
+#[test]
+fn rox_raw_string() -> TestResult {
+    run_test_eq(r#"print r"a\nb";"#, "Printing value of a\\nb")
+}
+
+#[test]
+fn rox_raw_string_path() -> TestResult {
+    run_test_eq(
+        r#"print r"C:\path\no\escapes";"#,
+        r"Printing value of C:\path\no\escapes",
+    )
+}
+
+#[test]
+fn rox_raw_string_multiline() -> TestResult {
+    run_test_eq("print r\"a\\t\nb\";", "Printing value of a\\t\nb")
+}
+
+#[test]
+fn rox_raw_string_concat() -> TestResult {
+    run_test_eq(r#"print r"\x41" + "\x41";"#, r"Printing value of \x41A")
+}
+
+#[test]
+fn rox_raw_string_unterminated() -> TestResult {
+    fail_test(r#"print r"abc"#, "[line 1:7] Error: Unterminated string.")
+}
+
+#[test]
+fn rox_r_identifier() -> TestResult {
+    run_test_eq("var r = 1;\nprint r;", "Printing value of 1")
+}
+
+#[test]
+fn rox_error_shows_lexeme() -> TestResult {
+    fail_test(
+        "var value = 1;\nvalue value;",
+        "Error at 'value': Expect ';' after expression",
+    )
+}
+
+#[test]
+fn rox_print_multiple() -> TestResult {
+    run_test_eq("print 1, 2, 3;", "Printing value of 1 2 3")
+}
+
+#[test]
+fn rox_print_multiple_mixed() -> TestResult {
+    run_test_eq(
+        "fun f() {}\nvar a = \"x\";\nprint a, nil, 1 + 1, f, [1, 2];",
+        "Printing value of x nil 2 <fn f/0> [1, 2]",
+    )
+}
+
+#[test]
+fn rox_print_trailing_comma() -> TestResult {
+    fail_test("print 1, ;", "[line 1:10] Error at ';': Expect expression.")
+}
+
+#[test]
+fn rox_function_equality_by_identity() -> TestResult {
+    run_test_eq(
+        r#"
+fun make() {
+    fun inner() {}
+    return inner;
+}
+var f = make();
+var g = f;
+print f == g;
+print make() == make();
+"#,
+        "Printing value of true\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_string_equality_by_content() -> TestResult {
+    run_test_eq(r#"print "ab" + "c" == "abc";"#, "Printing value of true")
+}
+
+#[test]
+fn rox_xor() -> TestResult {
+    run_test_eq(
+        "print true xor true;\nprint true xor false;\nprint false xor true;\nprint false xor false;",
+        "Printing value of false\nPrinting value of true\nPrinting value of true\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_xor_truthiness() -> TestResult {
+    run_test_eq("print nil xor 0;", "Printing value of true")
+}
+
+#[test]
+fn rox_xor_precedence() -> TestResult {
+    // `and` binds tighter than `xor`, which binds tighter than `or`
+    run_test_eq(
+        "print true xor true and false;\nprint true or true xor true;",
+        "Printing value of true\nPrinting value of true",
+    )
+}
+
+#[test]
+fn rox_xor_evaluates_both_sides() -> TestResult {
+    run_test_eq(
+        "var n = 0;\nfun bump() { n = n + 1; return true; }\nprint bump() xor bump();\nprint n;",
+        "Printing value of false\nPrinting value of 2",
+    )
+}
+
+#[test]
+fn rox_or_values() -> TestResult {
+    // 0 is truthy, only nil and false are falsey
+    run_test_eq(
+        "print 0 or \"x\";\nprint nil or \"x\";\nprint nil or false;\nprint false or 1;",
+        "Printing value of 0\nPrinting value of x\nPrinting value of false\nPrinting value of 1",
+    )
+}
+
+#[test]
+fn rox_and_values() -> TestResult {
+    run_test_eq(
+        "print nil and 5;\nprint 1 and 2;\nprint true and false;",
+        "nil\nPrinting value of 2\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_and_or_short_circuit() -> TestResult {
+    run_test_eq(
+        r#"
+var calls = 0;
+fun sideEffect() { calls = calls + 1; return true; }
+print false and sideEffect();
+print true or sideEffect();
+print calls;
+print nil or sideEffect();
+print calls;
+"#,
+        "Printing value of false\nPrinting value of true\nPrinting value of 0\nPrinting value of true\nPrinting value of 1",
+    )
+}
+
+#[test]
+fn rox_closures_share_captured_counter() -> TestResult {
+    run_test_eq(
+        r#"
+var inc = 0;
+var get = 0;
+fun make() {
+    var count = 0;
+    fun i() { count = count + 1; }
+    fun g() { return count; }
+    inc = i;
+    get = g;
+    i();
+    print g();
+    print count;
+}
+make();
+inc();
+inc();
+print get();
+"#,
+        "Printing value of 1\nPrinting value of 1\nPrinting value of 3",
+    )
+}
+
+#[test]
+fn rox_make_counter() -> TestResult {
+    run_test_eq(
+        r#"
+fun makeCounter() {
+    var i = 0;
+    fun count() {
+        i = i + 1;
+        return i;
+    }
+    return count;
+}
+var counter = makeCounter();
+print counter();
+print counter();
+print counter();
+var other = makeCounter();
+print other();
+"#,
+        "Printing value of 1\nPrinting value of 2\nPrinting value of 3\nPrinting value of 1",
+    )
+}
+
+// Classes are not implemented yet, `.` has no rule. Calls and indexing are the postfix operators
+// rox has and they chain left to right.
+#[test]
+fn rox_postfix_chains() -> TestResult {
+    run_test_eq(
+        r#"
+fun outer() {
+    fun inner() {
+        fun innermost() { return 3; }
+        return innermost;
+    }
+    return inner;
+}
+print outer()()();
+var grid = [[1, 2], [3, 4]];
+print grid[1][0];
+fun rows() { return grid; }
+print rows()[0][1];
+var fs = [outer];
+print fs[0]()()();
+"#,
+        "Printing value of 3\nPrinting value of 3\nPrinting value of 2\nPrinting value of 3",
+    )
+}
+
+#[test]
+fn rox_call_non_callable_with_arguments() -> TestResult {
+    fail_test(
+        "var x = \"f\";\nx(1, 2);",
+        "Runtime error: Can only call functions and classes, got string. [line 2]",
+    )
+}
+
+#[test]
+fn rox_call_nil() -> TestResult {
+    fail_test(
+        "fun f() {}\nf()();",
+        "Runtime error: Can only call functions and classes, got nil. [line 2]",
+    )
+}
+
+#[test]
+fn rox_type_of() -> TestResult {
+    run_test_eq(
+        r#"
+print type_of(1);
+print type_of("a");
+print type_of(nil);
+print type_of(true);
+print type_of([1]);
+fun f() {}
+print type_of(f);
+print type_of(type_of);
+"#,
+        "Printing value of number\nPrinting value of string\nPrinting value of nil\nPrinting value of bool\nPrinting value of list\nPrinting value of function\nPrinting value of function",
+    )
+}
+
+#[test]
+fn rox_type_of_arity() -> TestResult {
+    fail_test(
+        "type_of(1, 2);",
+        "Runtime error: type_of expects one argument.",
+    )
+}
+
+#[test]
+fn rox_stack_overflow_on_huge_list() -> TestResult {
+    // the elements are pushed before the list is built, far more than the stack holds
+    let elements = vec!["1"; 20000].join(", ");
+    fail_test(
+        &format!("var xs = [{}];", elements),
+        "Runtime error: Stack overflow. [line 1]",
+    )
+}
+
+#[test]
+fn rox_infinite_recursion() -> TestResult {
+    fail_test(
+        "fun f() {\n    return f();\n}\nf();",
+        "Runtime error: Stack overflow. [line 2]",
+    )
+}
+
+#[test]
+fn rox_infinite_recursion_exit_code() -> TestResult {
+    // a runtime error, not a panic
+    exit_code_test("fun f() { f(); }\nf();", 70)
+}
+
+#[test]
+fn rox_too_many_upvalues() -> TestResult {
+    let locals = |prefix: &str| {
+        (0..150)
+            .map(|idx| format!("var {}{} = {};", prefix, idx, idx))
+            .collect::<String>()
+    };
+    let reads = (0..150)
+        .map(|idx| format!("a{} + b{};", idx, idx))
+        .collect::<String>();
+    fail_test(
+        &format!(
+            "fun outer() {{ {} fun middle() {{ {} fun inner() {{ {} }} }} }}",
+            locals("a"),
+            locals("b"),
+            reads
+        ),
+        "Error at 'a128': Too many closure variables in function.",
+    )
+}
+
+#[test]
+fn rox_closure_reads_upvalue_twice() -> TestResult {
+    run_test_eq(
+        "fun outer() {\n  var a = 1;\n  var b = 2;\n  fun inner() {\n    print b;\n    print b;\n  }\n  inner();\n}\nouter();",
+        "Printing value of 2\nPrinting value of 2",
+    )
+}
+
+#[test]
+fn rox_misspelled_function_warns() -> TestResult {
+    fail_test(
+        "fun greet() {\n  print \"hi\";\n}\ngret();",
+        "[line 4:1] Warning: Undefined variable 'gret'.",
+    )
+}
+
+#[test]
+fn rox_forward_reference_to_global() -> TestResult {
+    // globals are late bound, a function may call another one defined further down
+    warning_test(
+        "fun main() {\n  helper();\n}\nfun helper() {\n  print len([1, 2]);\n}\nmain();",
+        "",
+    )
+}
+
+#[test]
+fn rox_dump_bytecode() -> TestResult {
+    let mut file = NamedTempFile::new()?;
+    writeln!(
+        file,
+        "fun add(a, b) {{\n  return a + b;\n}}\nprint add(1, 2);"
+    )?;
+
+    let mut cmd = Command::cargo_bin("rox")?;
+    cmd.arg("--dump-bytecode").arg(file.path());
+    let output = cmd.output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    println!("stdout: {}", stdout);
+
+    assert!(output.status.success());
+    assert!(stdout.contains("== Begin to disassemble script =="));
+    assert!(stdout.contains("== Begin to disassemble add =="));
+    assert!(stdout.contains("OP CODE:Closure"));
+    assert!(stdout.contains("OP CODE:Get Local"));
+    assert!(stdout.contains("OP CODE:Call"));
+    // the script is compiled but never run
+    assert!(!stdout.contains("Printing value of 3"));
+
+    Ok(())
+}
+
+#[test]
+fn rox_invalid_utf8_in_string() -> TestResult {
+    let mut file = NamedTempFile::new()?;
+    file.write_all(b"print \"a\xffb\";\n")?;
+
+    let output = Command::cargo_bin("rox")?.arg(file.path()).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    println!("stderr: {}", stderr);
+
+    // a compile error rather than a panic
+    assert_eq!(Some(65), output.status.code());
+    assert!(stderr.contains("[line 1:7] Error at '\"a\u{fffd}b\"': Strings must be valid UTF-8."));
+
+    Ok(())
+}
+
+#[test]
+fn rox_invalid_utf8_outside_string() -> TestResult {
+    let mut file = NamedTempFile::new()?;
+    file.write_all(b"var \xc3\xa9 = 1;\n")?;
+
+    let output = Command::cargo_bin("rox")?.arg(file.path()).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    println!("stderr: {}", stderr);
+
+    assert_eq!(Some(65), output.status.code());
+    assert!(stderr.contains("Unexpected character"));
+
+    Ok(())
+}
+
+#[test]
+fn rox_runtime_error_line_of_operator() -> TestResult {
+    // the error is reported where the `+` and the call start, not where their operands end
+    fail_test(
+        "fun f(a) {\n  var b = a +\n    nil;\n  return b;\n}\nf(\n  1\n);",
+        "Runtime error: operands must be two numbers or two strings [line 2]\n[line 2] in f\n[line 6] in script",
+    )
+}
+
+#[test]
+fn rox_runtime_error_at_first_instruction_of_function() -> TestResult {
+    fail_test(
+        "fun f() {\n  missing();\n}\nf();",
+        "Runtime error: undefined variable 'missing' [line 2]\n[line 2] in f\n[line 4] in script",
+    )
+}
+
+#[test]
+fn rox_power() -> TestResult {
+    run_test_eq("print 2 ** 10;", "Printing value of 1024")
+}
+
+#[test]
+fn rox_power_is_right_associative() -> TestResult {
+    run_test_eq("print 2 ** 3 ** 2;", "Printing value of 512")
+}
+
+#[test]
+fn rox_power_binds_tighter_than_factor_and_unary() -> TestResult {
+    run_test_eq(
+        "print 2 * 3 ** 2;\nprint -2 ** 2;",
+        "Printing value of 18\nPrinting value of -4",
+    )
+}
+
+#[test]
+fn rox_power_needs_two_numbers() -> TestResult {
+    fail_test("print 2 ** \"a\";", "operands must be two numbers")
+}
+
+#[test]
+fn rox_coalesce_nil() -> TestResult {
+    run_test_eq("print nil ?? 5;", "Printing value of 5")
+}
+
+#[test]
+fn rox_coalesce_skips_right_operand() -> TestResult {
+    // `f` is never called when the left operand is not nil
+    run_test_eq(
+        "fun f() { print \"called\"; return 5; }\nprint 3 ?? f();\nprint false ?? f();",
+        "Printing value of 3\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_coalesce_chain() -> TestResult {
+    run_test_eq("print nil ?? nil ?? 1 == 1;", "Printing value of true")
+}
+
+#[test]
+fn rox_unary_plus() -> TestResult {
+    run_test_eq(
+        "print +2;\nprint 1 - +2;",
+        "Printing value of 2\nPrinting value of -1",
+    )
+}
+
+#[test]
+fn rox_double_negation() -> TestResult {
+    run_test_eq(
+        "print - -2;\nprint --2;",
+        "Printing value of 2\nPrinting value of 2",
+    )
+}
+
+#[test]
+fn rox_unary_plus_needs_a_number() -> TestResult {
+    // `+` never converts its operand, `+"a"` is an error just like `-"a"`
+    fail_test("print +\"a\";", "Runtime error: operand must be a number")
+}
+
+#[test]
+fn rox_chained_comparison() -> TestResult {
+    fail_test(
+        "print 1 < 2 < 3;",
+        "[line 1:13] Error at '<': Comparison operators can't be chained, combine the comparisons with 'and'.",
+    )
+}
+
+#[test]
+fn rox_sleep_and_now_millis() -> TestResult {
+    // only the types are checked, the values depend on the clock
+    run_test_eq(
+        "print sleep(0);\nprint type_of(now_millis());",
+        "nil\nPrinting value of number",
+    )
+}
+
+#[test]
+fn rox_sleep_too_long() -> TestResult {
+    fail_test(
+        "sleep(10 ** 300);",
+        "sleep expects a non negative number of milliseconds.",
+    )
+}
+
+#[test]
+fn rox_comparison_needs_two_numbers() -> TestResult {
+    fail_test(
+        "print 1 < \"a\";",
+        "Runtime error: operands must be two numbers [line 1]",
+    )
+}
+
+#[test]
+fn rox_crlf_error_line() -> TestResult {
+    fail_test(
+        "var a = 1;\r\n// comment\r\nprint a +\r\n  nil;",
+        "Runtime error: operands must be two numbers or two strings [line 3]",
+    )
+}
+
+#[test]
+fn rox_assert_passes() -> TestResult {
+    warning_test(
+        "assert(1 < 2);\nassert_eq(\"a\" + \"b\", \"ab\");\nassert_eq([1, 2], [1, 2]);",
+        "",
+    )?;
+    run_test_eq("assert(true);", "")
+}
+
+#[test]
+fn rox_assert_fails() -> TestResult {
+    fail_test("assert(nil);", "Runtime error: assertion failed [line 1]")?;
+    exit_code_test("assert(false);", 70)
+}
+
+#[test]
+fn rox_assert_eq_fails() -> TestResult {
+    fail_test(
+        "assert_eq(1 + 1, 3);",
+        "Runtime error: assertion failed: 2 != 3 [line 1]",
+    )
+}
+
+#[test]
+fn rox_print_function_arity() -> TestResult {
+    run_test_eq(
+        "fun foo(a) { return a; }\nprint foo;\nvar bar = fun(a, b) { return a + b; };\nprint bar;",
+        "<fn foo/1>\n<fn lambda/2>",
+    )
+}
+
+#[test]
+fn rox_global_holding_nil() -> TestResult {
+    // a global set to nil is still defined
+    run_test_eq(
+        "var a;\nprint a;\na = nil;\nprint a ?? 1;",
+        "nil\nPrinting value of 1",
+    )
+}
+
+#[test]
+fn rox_const() -> TestResult {
+    run_test_eq(
+        "const a = 1;\nfun f() { const b = a + 1; return b; }\nprint f();",
+        "Printing value of 2",
+    )
+}
+
+#[test]
+fn rox_const_reassignment() -> TestResult {
+    fail_test(
+        "const a = 1;\na = 2;",
+        "[line 2:1] Error at 'a': Can't assign to constant 'a'.",
+    )
+}
+
+#[test]
+fn rox_const_redefinition() -> TestResult {
+    fail_test(
+        "const a = 1;\nvar a = 2;",
+        "[line 2:5] Error at 'a': Can't redefine constant 'a'.",
+    )
+}
+
+#[test]
+fn rox_repl_const_reassignment() -> TestResult {
+    run_repl_test(
+        "const a = 1;\na = 2;\nvar a = 3;\nprint a;\n",
+        "Printing value of 1",
+    )
+}
+
+#[test]
+fn rox_var_without_initializer_is_nil() -> TestResult {
+    run_test_eq("var a;\nprint a == nil;", "Printing value of true")
+}
+
+#[test]
+fn rox_var_list_globals() -> TestResult {
+    run_test_eq(
+        "var a = 1, b = a + 1, c;\nprint a, b, c;",
+        "Printing value of 1 2 nil",
+    )
+}
+
+#[test]
+fn rox_var_list_locals() -> TestResult {
+    run_test_eq(
+        "{\n  var a = 1, b = a + 1;\n  var c = a + b, d = c * 2;\n  print d;\n}",
+        "Printing value of 6",
+    )
+}
+
+#[test]
+fn rox_var_list_for_initializer() -> TestResult {
+    run_test_eq(
+        "for (var i = 0, j = 10; i < 2; i = i + 1) {\n  print i + j;\n}",
+        "Printing value of 10\nPrinting value of 11",
+    )
+}
+
+#[test]
+fn rox_unused_local() -> TestResult {
+    warning_test("{ var a = 1; }", "[line 1:7] Warning: Unused variable 'a'.")
+}
+
+#[test]
+fn rox_unused_local_in_function() -> TestResult {
+    warning_test(
+        r#"
+            fun f(x) {
+                var y = 2;
+            }
+            f(1);
+        "#,
+        "[line 3:21] Warning: Unused variable 'y'.",
+    )
+}
+
+#[test]
+fn rox_used_locals_do_not_warn() -> TestResult {
+    warning_test(
+        r#"
+            fun f(unused) {
+                var a = 1;
+                var b = 2;
+                var _c = 3;
+                fun g() {
+                    return b;
+                }
+                print a;
+                return g;
+            }
+            f(1);
+        "#,
+        "",
+    )
+}
+
+#[test]
+fn rox_local_in_own_initializer() -> TestResult {
+    fail_test(
+        "{ var a = a; }",
+        "[line 1:11] Error at 'a': Can't read local variable in its own initializer.",
+    )
+}
+
+#[test]
+fn rox_shadowing_local_in_own_initializer() -> TestResult {
+    fail_test(
+        r#"
+            {
+                var a = 1;
+                {
+                    var a = a + 1;
+                }
+            }
+        "#,
+        "Can't read local variable in its own initializer.",
+    )
+}
+
+#[test]
+fn rox_closure_in_own_initializer() -> TestResult {
+    fail_test(
+        "{ var f = fun() { return f; }; }",
+        "Can't read local variable in its own initializer.",
+    )
+}
+
+#[test]
+fn rox_constant_folding() -> TestResult {
+    run_test_eq(
+        "print 1 + 2 * 3;\nprint -(2 - 4) / 4;\nprint 7 % 4;\nprint 2 ** 3 ** 2;",
+        "Printing value of 7\nPrinting value of 0.5\nPrinting value of 3\nPrinting value of 512",
+    )
+}
+
+#[test]
+fn rox_not_binds_tighter_than_equality() -> TestResult {
+    run_test_eq(
+        "print !false == true;\nprint !1 == 2;",
+        "Printing value of true\nPrinting value of false",
+    )
+}
+
+#[test]
+fn rox_bang_is_not_infix() -> TestResult {
+    fail_test("print 1 ! 2;", "Error at '!': Expect ';' after value")
+}
+
+#[test]
+fn rox_modulo() -> TestResult {
+    run_test_eq(
+        "var a = 7;\nprint a % 3;\nprint -a % 3;\nprint 7.5 % 2;\nprint 1 + a % 4 * 2;",
+        "Printing value of 1\nPrinting value of -1\nPrinting value of 1.5\nPrinting value of 7",
+    )
+}
+
+#[test]
+fn rox_divide_by_zero() -> TestResult {
+    fail_test("1 / 0;", "Runtime error: Division by zero. [line 1]")
+}
+
+#[test]
+fn rox_modulo_by_zero() -> TestResult {
+    fail_test("1 % 0;", "Runtime error: Division by zero. [line 1]")
+}
+
+#[test]
+fn rox_divide_by_zero_variable() -> TestResult {
+    fail_test(
+        "var zero = 0;\nprint 1 / -zero;",
+        "Runtime error: Division by zero. [line 2]",
+    )
+}
+
+#[test]
+fn rox_self_containing_list() -> TestResult {
+    run_test_eq(
+        "var l = [0];\nl[0] = l;\nprint l;\nprint l == l;\npush(l, l);\nprint l;",
+        "Printing value of [[...]]\nPrinting value of true\nPrinting value of [[...], [...]]",
+    )
+}
+
+#[test]
+fn rox_mutually_containing_lists() -> TestResult {
+    run_test_eq(
+        "var a = [];\nvar b = [];\npush(a, b);\npush(b, a);\nprint a == b;",
+        "Printing value of true",
+    )
+}
+
+#[test]
+fn rox_distinct_self_containing_lists() -> TestResult {
+    run_test_eq(
+        "var a = [1];\npush(a, a);\nvar b = [1];\npush(b, b);\nprint a == b;\npush(b, 2);\nprint a == b;",
+        "Printing value of true\nPrinting value of false",
+    )
+}